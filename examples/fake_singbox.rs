@@ -0,0 +1,515 @@
+//! Stand-in for sing-box in integration tests, see `tests/common/mod.rs`.
+//!
+//! Supports `version` and `run -c <config> [-D <dir>]`. Inbounds are plain
+//! TCP echo servers whose traffic is counted in the v2ray API's
+//! `StatsService`, logs are written in sing-box's format to `log.output` or
+//! stderr, SIGHUP reloads the config and SIGTERM or SIGINT exits.
+//!
+//! Misbehaviour is requested through the environment:
+//! - `FAKE_SINGBOX_CRASH_AFTER_MS`: exit with status 1 that long after starting.
+//! - `FAKE_SINGBOX_CRASH_MARKER`: only crash while this file doesn't exist,
+//!   creating it, so that a restarted process keeps running.
+//! - `FAKE_SINGBOX_HANG=start`: never listen, so the process doesn't get ready.
+//! - `FAKE_SINGBOX_HANG=stop`: ignore SIGTERM and SIGINT.
+
+#[cfg(unix)]
+#[tokio::main]
+async fn main() {
+    std::process::exit(fake::main().await);
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("fake_singbox only runs on unix");
+    std::process::exit(1);
+}
+
+#[cfg(unix)]
+mod fake {
+    use parking_lot::Mutex;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpSocket, TcpStream};
+    use tokio::signal::unix::{SignalKind, signal};
+    use tokio::task::JoinHandle;
+    use tonic::transport::Server;
+    use tonic::transport::server::TcpIncoming;
+    use tonic::{Request, Response, Status};
+
+    use next_proxies_pod::api::v2ray_api::v2rayapi::stats_service_server::{
+        StatsService, StatsServiceServer,
+    };
+    use next_proxies_pod::api::v2ray_api::v2rayapi::{
+        GetStatsRequest, GetStatsResponse, QueryStatsRequest, QueryStatsResponse, Stat,
+        SysStatsRequest, SysStatsResponse,
+    };
+
+    pub async fn main() -> i32 {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        match args.first().map(String::as_str) {
+            Some("version") => {
+                println!("sing-box version 1.12.0-fake");
+                println!();
+                println!("Environment: go1.23.4 linux/amd64");
+                println!("Tags: with_gvisor,with_quic,with_utls,with_v2ray_api");
+                println!("Revision: fake");
+                0
+            }
+            Some("run") => match flag(&args, "-c") {
+                Some(config) => run(Path::new(config)).await,
+                None => {
+                    eprintln!("FATAL[0000] missing -c <config>");
+                    1
+                }
+            },
+            _ => {
+                eprintln!("usage: fake_singbox version | run -c <config> [-D <dir>]");
+                1
+            }
+        }
+    }
+
+    fn flag<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+        let index = args.iter().position(|arg| arg == name)?;
+        args.get(index + 1).map(String::as_str)
+    }
+
+    async fn run(path: &Path) -> i32 {
+        let config = match load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("FATAL[0000] decode config at {}: {}", path.display(), e);
+                return 1;
+            }
+        };
+
+        let hang = std::env::var("FAKE_SINGBOX_HANG").unwrap_or_default();
+        let stats = Stats::default();
+        let mut logger = Logger::new(&config);
+        let mut services = Vec::new();
+
+        if hang == "start" {
+            logger.log("INFO", "", "hanging before start");
+        } else {
+            match serve(&config, &stats, &logger).await {
+                Ok(started) => services = started,
+                Err(e) => {
+                    logger.log("FATAL", "", &format!("start service: {}", e));
+                    return 1;
+                }
+            }
+            logger.log("INFO", "", "sing-box started (0.00s)");
+        }
+
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+
+        let crash = crash_after();
+        tokio::pin!(crash);
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    // Like sing-box, a config that doesn't load keeps the old one running
+                    let config = match load(path) {
+                        Ok(config) => config,
+                        Err(e) => {
+                            logger.log("ERROR", "", &format!("reload service: {}", e));
+                            continue;
+                        }
+                    };
+
+                    stop_services(&mut services).await;
+                    logger = Logger::new(&config);
+                    match serve(&config, &stats, &logger).await {
+                        Ok(started) => services = started,
+                        Err(e) => {
+                            logger.log("FATAL", "", &format!("reload service: {}", e));
+                            return 1;
+                        }
+                    }
+                    logger.log("INFO", "", "sing-box reloaded");
+                }
+                _ = terminate.recv() => {
+                    if hang == "stop" {
+                        logger.log("WARN", "", "ignoring SIGTERM");
+                        continue;
+                    }
+                    break;
+                }
+                _ = interrupt.recv() => {
+                    if hang == "stop" {
+                        logger.log("WARN", "", "ignoring SIGINT");
+                        continue;
+                    }
+                    break;
+                }
+                _ = &mut crash => {
+                    logger.log("FATAL", "", "crashing as requested");
+                    return 1;
+                }
+            }
+        }
+
+        stop_services(&mut services).await;
+        logger.log("INFO", "", "sing-box closed");
+        0
+    }
+
+    /// Reads and checks the parts of the config the fake uses.
+    fn load(path: &Path) -> Result<Value, String> {
+        let content = fs::read(path).map_err(|e| e.to_string())?;
+        let config: Value = serde_json::from_slice(&content).map_err(|e| e.to_string())?;
+
+        let inbounds = config["inbounds"].as_array().ok_or("missing inbounds")?;
+        for (i, inbound) in inbounds.iter().enumerate() {
+            if !inbound["tag"].is_string() || !inbound["type"].is_string() {
+                return Err(format!("inbounds[{}]: missing type or tag", i));
+            }
+            if inbound["listen_port"]
+                .as_u64()
+                .is_none_or(|port| port > u16::MAX as u64)
+            {
+                return Err(format!("inbounds[{}].listen_port: invalid port", i));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Resolves after `FAKE_SINGBOX_CRASH_AFTER_MS`, never if it is unset or
+    /// the crash marker already exists.
+    async fn crash_after() {
+        let delay = std::env::var("FAKE_SINGBOX_CRASH_AFTER_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .map(Duration::from_millis);
+
+        let crashed = match std::env::var_os("FAKE_SINGBOX_CRASH_MARKER").map(PathBuf::from) {
+            Some(marker) if marker.exists() => true,
+            Some(marker) => {
+                let _ = File::create(marker);
+                false
+            }
+            None => false,
+        };
+
+        match delay {
+            Some(delay) if !crashed => tokio::time::sleep(delay).await,
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// Starts the inbounds and the v2ray API.
+    async fn serve(
+        config: &Value,
+        stats: &Stats,
+        logger: &Logger,
+    ) -> Result<Vec<JoinHandle<()>>, String> {
+        let mut services = Vec::new();
+
+        for inbound in config["inbounds"].as_array().into_iter().flatten() {
+            let tag = inbound["tag"].as_str().unwrap_or_default().to_string();
+            stats.register(&format!("inbound>>>{}>>>traffic>>>uplink", tag));
+            stats.register(&format!("inbound>>>{}>>>traffic>>>downlink", tag));
+            for user in inbound["users"].as_array().into_iter().flatten() {
+                let name = user["name"].as_str().unwrap_or_default();
+                stats.register(&format!("user>>>{}>>>traffic>>>uplink", name));
+                stats.register(&format!("user>>>{}>>>traffic>>>downlink", name));
+            }
+
+            if inbound["network"] == "udp" {
+                continue;
+            }
+
+            // Binding the IPv4 wildcard is enough for the pod's readiness probe
+            let listen = match inbound["listen"].as_str().unwrap_or_default() {
+                "" | "::" => "0.0.0.0",
+                listen => listen,
+            };
+            let port = inbound["listen_port"].as_u64().unwrap_or_default() as u16;
+            let addr: SocketAddr = format!("{}:{}", listen, port)
+                .parse()
+                .or_else(|_| format!("[{}]:{}", listen, port).parse())
+                .map_err(|e| format!("inbound/{}[{}]: {}", inbound["type"], tag, e))?;
+            let reuse_port = inbound["reuse_addr"] == true;
+            let listener = bind(addr, reuse_port).map_err(|e| {
+                format!(
+                    "inbound/{}[{}]: listen {}: {}",
+                    inbound["type"], tag, addr, e
+                )
+            })?;
+
+            let component = format!(
+                "inbound/{}[{}]",
+                inbound["type"].as_str().unwrap_or_default(),
+                tag
+            );
+            logger.log(
+                "INFO",
+                &component,
+                &format!("tcp server started at {}", addr),
+            );
+            services.push(tokio::spawn(accept(
+                listener,
+                tag,
+                component,
+                stats.clone(),
+                logger.clone(),
+            )));
+        }
+
+        if let Some(listen) = config
+            .pointer("/experimental/v2ray_api/listen")
+            .and_then(Value::as_str)
+        {
+            // `localhost` may resolve to an IPv6 address the host can't bind
+            let addrs = tokio::net::lookup_host(listen)
+                .await
+                .map_err(|e| format!("v2ray-api: invalid listen address {}: {}", listen, e))?;
+            let (addr, listener) = addrs
+                .filter_map(|addr| Some((addr, bind(addr, false).ok()?)))
+                .next()
+                .ok_or_else(|| format!("v2ray-api: listen {}: no address to bind", listen))?;
+            let incoming = TcpIncoming::from_listener(listener, true, None)
+                .map_err(|e| format!("v2ray-api: {}", e))?;
+
+            logger.log(
+                "INFO",
+                "v2ray-api",
+                &format!("grpc server started at {}", addr),
+            );
+            let service = StatsServiceServer::new(stats.clone());
+            services.push(tokio::spawn(async move {
+                let _ = Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming(incoming)
+                    .await;
+            }));
+        }
+
+        Ok(services)
+    }
+
+    async fn stop_services(services: &mut Vec<JoinHandle<()>>) {
+        for service in services.drain(..) {
+            service.abort();
+            let _ = service.await;
+        }
+    }
+
+    /// Binds with SO_REUSEADDR so a reload can listen on the same ports again,
+    /// and SO_REUSEPORT when the inbound asks for it, as sing-box does.
+    fn bind(addr: SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        if reuse_port {
+            socket.set_reuseport(true)?;
+        }
+        socket.bind(addr)?;
+        socket.listen(1024)
+    }
+
+    async fn accept(
+        listener: TcpListener,
+        tag: String,
+        component: String,
+        stats: Stats,
+        logger: Logger,
+    ) {
+        static CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+        while let Ok((stream, peer)) = listener.accept().await {
+            let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+            logger.log(
+                "INFO",
+                &format!("[{} 0ms] {}", id, component),
+                &format!("inbound connection from {}", peer),
+            );
+            tokio::spawn(echo(stream, tag.clone(), stats.clone()));
+        }
+    }
+
+    /// Sends everything back, counting it both ways before writing so the
+    /// stats are up to date once the client has read its echo.
+    async fn echo(mut stream: TcpStream, tag: String, stats: Stats) {
+        let mut buf = [0; 4096];
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+            stats.add(&format!("inbound>>>{}>>>traffic>>>uplink", tag), n);
+            stats.add(&format!("inbound>>>{}>>>traffic>>>downlink", tag), n);
+            if stream.write_all(&buf[..n]).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Writes lines like sing-box: `+0800 2024-12-05 12:00:00 INFO inbound/...: message`
+    /// with `log.timestamp` or an output file, `INFO[0012] inbound/...: message`
+    /// in color on stderr otherwise.
+    #[derive(Clone)]
+    struct Logger {
+        output: Option<Arc<Mutex<File>>>,
+        disabled: bool,
+        timestamp: bool,
+        color: bool,
+        started: Instant,
+    }
+
+    impl Logger {
+        fn new(config: &Value) -> Self {
+            let output = config["log"]["output"].as_str().and_then(|path| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .ok()
+                    .map(|file| Arc::new(Mutex::new(file)))
+            });
+
+            Self {
+                timestamp: config["log"]["timestamp"] == true || output.is_some(),
+                color: output.is_none() && config["log"]["disable_color"] != true,
+                output,
+                disabled: config["log"]["disabled"] == true,
+                started: Instant::now(),
+            }
+        }
+
+        fn log(&self, level: &str, component: &str, message: &str) {
+            if self.disabled {
+                return;
+            }
+
+            let prefix = match (self.timestamp, self.color) {
+                (true, _) => format!(
+                    "{} {}",
+                    chrono::Local::now().format("%z %Y-%m-%d %H:%M:%S"),
+                    level
+                ),
+                (false, color) => {
+                    let level = match (color, level) {
+                        (false, level) => level.to_string(),
+                        (true, "WARN") => format!("\x1b[33m{}\x1b[0m", level),
+                        (true, "ERROR" | "FATAL") => format!("\x1b[31m{}\x1b[0m", level),
+                        (true, level) => format!("\x1b[36m{}\x1b[0m", level),
+                    };
+                    format!("{}[{:04}]", level, self.started.elapsed().as_secs())
+                }
+            };
+            let line = match component {
+                "" => format!("{} {}\n", prefix, message),
+                component => format!("{} {}: {}\n", prefix, component, message),
+            };
+            match &self.output {
+                Some(output) => {
+                    let _ = output.lock().write_all(line.as_bytes());
+                }
+                None => eprint!("{}", line),
+            }
+        }
+    }
+
+    /// Counters served over `StatsService`, kept across reloads.
+    #[derive(Clone, Default)]
+    struct Stats(Arc<Mutex<BTreeMap<String, i64>>>);
+
+    impl Stats {
+        fn register(&self, name: &str) {
+            self.0.lock().entry(name.to_string()).or_default();
+        }
+
+        fn add(&self, name: &str, n: usize) {
+            *self.0.lock().entry(name.to_string()).or_default() += n as i64;
+        }
+    }
+
+    #[tonic::async_trait]
+    impl StatsService for Stats {
+        async fn get_stats(
+            &self,
+            request: Request<GetStatsRequest>,
+        ) -> Result<Response<GetStatsResponse>, Status> {
+            let request = request.into_inner();
+            let mut counters = self.0.lock();
+            let Some(value) = counters.get_mut(&request.name) else {
+                return Err(Status::not_found(format!("{} not found", request.name)));
+            };
+
+            let stat = Stat {
+                name: request.name,
+                value: *value,
+            };
+            if request.reset {
+                *value = 0;
+            }
+            Ok(Response::new(GetStatsResponse { stat: Some(stat) }))
+        }
+
+        async fn query_stats(
+            &self,
+            request: Request<QueryStatsRequest>,
+        ) -> Result<Response<QueryStatsResponse>, Status> {
+            let request = request.into_inner();
+            let mut patterns = request.patterns;
+            if patterns.is_empty() {
+                patterns.push(request.pattern);
+            }
+            let regexes = match request.regexp {
+                true => patterns
+                    .iter()
+                    .map(|pattern| regex::Regex::new(pattern))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Status::invalid_argument(e.to_string()))?,
+                false => Vec::new(),
+            };
+
+            let mut stat = Vec::new();
+            for (name, value) in self.0.lock().iter_mut() {
+                let matches = match request.regexp {
+                    true => regexes.iter().any(|regex| regex.is_match(name)),
+                    false => patterns
+                        .iter()
+                        .any(|pattern| name.contains(pattern.as_str())),
+                };
+                if !matches {
+                    continue;
+                }
+
+                stat.push(Stat {
+                    name: name.clone(),
+                    value: *value,
+                });
+                if request.reset {
+                    *value = 0;
+                }
+            }
+
+            Ok(Response::new(QueryStatsResponse { stat }))
+        }
+
+        async fn get_sys_stats(
+            &self,
+            _request: Request<SysStatsRequest>,
+        ) -> Result<Response<SysStatsResponse>, Status> {
+            Ok(Response::new(SysStatsResponse {
+                uptime: 1,
+                ..Default::default()
+            }))
+        }
+    }
+}
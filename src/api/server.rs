@@ -1,62 +1,408 @@
 use super::v2ray_api::StatsFormatResponse;
-use crate::config::ConfigResponse;
-use reqwest::{Client, header::HeaderMap};
-use std::error::Error;
-use tracing::info;
+use crate::config::{ConfigError, ConfigResponse, diff::ConfigDiff};
+use crate::process::log::ErrorLogReport;
+use crate::process::version::SingBoxVersion;
+use parking_lot::Mutex;
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{Client, Response, StatusCode, Url};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{error, info};
+
+/// Response headers kept in `PanelError::Status`, the rest are noise.
+const ERROR_HEADERS: [&str; 4] = [
+    "content-type",
+    "www-authenticate",
+    "retry-after",
+    "x-request-id",
+];
+
+/// Bytes of an error response body kept in `PanelError::Status`.
+const ERROR_BODY_LIMIT: usize = 1024;
+
+/// Wait before retrying after the panel rejected the token, doubled on every
+/// rejection up to `AUTH_RETRY_MAX`.
+const AUTH_RETRY_DELAY: Duration = Duration::from_secs(60);
+const AUTH_RETRY_MAX: Duration = Duration::from_secs(15 * 60);
+
+/// Failure to talk to the panel.
+#[derive(Debug, Error)]
+pub enum PanelError {
+    /// The request didn't get a response, from DNS to reading the body.
+    #[error("panel request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    /// A non-2xx response, with the headers in `ERROR_HEADERS` and the body
+    /// truncated to `ERROR_BODY_LIMIT` bytes.
+    #[error("panel responded with {status}{}: {body}", format_headers(.headers))]
+    Status {
+        status: StatusCode,
+        headers: Vec<(String, String)>,
+        body: String,
+    },
+
+    /// The request wasn't sent, the panel rejected the token recently.
+    #[error("panel rejected the token with {status}, retrying in {}s", .retry_in.as_secs())]
+    AuthBackoff {
+        status: StatusCode,
+        retry_in: Duration,
+    },
+
+    #[error("invalid panel url {url}: {reason}")]
+    Url { url: String, reason: String },
+
+    /// The token can't be sent as a header, it holds a newline or another
+    /// control character.
+    #[error("the token contains characters not allowed in a header")]
+    InvalidToken,
+
+    #[error("failed to serialize the report: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+impl PanelError {
+    /// Whether the panel rejected the token, retrying won't help until it's
+    /// fixed on either side.
+    pub fn is_auth_failure(&self) -> bool {
+        match self {
+            PanelError::Status { status, .. } => is_auth_status(*status),
+            PanelError::AuthBackoff { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// The panel's last rejection of the token, shared by every clone of a
+/// `ServerFetch` so none of them keep hammering it.
+#[derive(Clone, Debug)]
+pub struct AuthFailure {
+    pub status: StatusCode,
+    /// Rejections in a row.
+    pub failures: u32,
+    pub retry_at: Instant,
+}
+
+/// Body posted to the panel on every reporting cycle.
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsReport {
+    #[serde(flatten)]
+    pub stats: StatsFormatResponse,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub config_diffs: Vec<ConfigDiff>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub core_version: Option<SingBoxVersion>,
+
+    /// Why the config fetched in this cycle couldn't be applied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_error: Option<String>,
+}
 
 #[derive(Debug, Clone)]
 pub struct ServerFetch {
     pub url: String,
+    /// Where sing-box logs are posted, `<url>/logs` when unset.
+    pub logs_url: Option<String>,
     headers: HeaderMap,
     client: Client,
+    auth_failure: Arc<Mutex<Option<AuthFailure>>>,
 }
 
 impl ServerFetch {
-    pub fn new(url: String, authorization: String) -> Self {
+    pub fn new(url: String, authorization: String) -> Result<Self, PanelError> {
         let mut headers = HeaderMap::new();
-        headers.insert("X-Proxy-Authorization", authorization.parse().unwrap());
+        headers.insert("X-Proxy-Authorization", auth_header(&authorization)?);
 
         let client = Client::new();
 
-        Self {
+        Ok(Self {
             url,
+            logs_url: None,
             headers,
             client,
+            auth_failure: Default::default(),
+        })
+    }
+
+    /// Set while the panel is rejecting the token.
+    pub fn auth_failure(&self) -> Option<AuthFailure> {
+        self.auth_failure.lock().clone()
+    }
+
+    /// Fails without a request while backing off from an auth failure.
+    fn check_auth(&self) -> Result<(), PanelError> {
+        match &*self.auth_failure.lock() {
+            Some(failure) if failure.retry_at > Instant::now() => Err(PanelError::AuthBackoff {
+                status: failure.status,
+                retry_in: failure.retry_at - Instant::now(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Body of a successful response, the status, headers and body otherwise.
+    /// Tracks auth failures, backing off from the panel while it rejects the
+    /// token.
+    async fn success_body(&self, response: Response) -> Result<String, PanelError> {
+        let status = response.status();
+        let headers = ERROR_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = response.headers().get(*name)?;
+                Some((name.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect();
+
+        self.track_auth(status);
+
+        match status.is_success() {
+            true => Ok(response.text().await?),
+            false => Err(PanelError::Status {
+                status,
+                headers,
+                body: error_body(response).await?,
+            }),
         }
     }
 
-    pub async fn get_config(&mut self) -> Result<ConfigResponse, Box<dyn Error>> {
+    /// Backs off from the panel while it rejects the token.
+    fn track_auth(&self, status: StatusCode) {
+        let mut auth_failure = self.auth_failure.lock();
+        if is_auth_status(status) {
+            let failures = auth_failure.as_ref().map_or(0, |f| f.failures) + 1;
+            let delay = AUTH_RETRY_DELAY
+                .saturating_mul(2u32.saturating_pow(failures - 1))
+                .min(AUTH_RETRY_MAX);
+            error!(
+                "The panel rejected the token with {}, retrying in {}s",
+                status,
+                delay.as_secs()
+            );
+            *auth_failure = Some(AuthFailure {
+                status,
+                failures,
+                retry_at: Instant::now() + delay,
+            });
+        } else if auth_failure.take().is_some() {
+            info!("The panel accepted the token again");
+        }
+    }
+
+    pub async fn get_config(&mut self) -> Result<ConfigResponse, ConfigError> {
+        self.check_auth()?;
         let response = self
             .client
             .get(&self.url)
             .headers(self.headers.clone())
             .send()
-            .await?;
+            .await
+            .map_err(PanelError::from)?;
 
-        match response.status().is_success() {
-            true => {
-                let body = response.text().await?;
-                Ok(serde_json::from_str(&body)?)
-            }
-            false => Err("Error fetching config".into()),
-        }
+        let body = self.success_body(response).await?;
+        ConfigResponse::parse(&body)
     }
 
-    pub async fn post_stats(&mut self, stats: StatsFormatResponse) -> Result<(), Box<dyn Error>> {
+    pub async fn post_stats(&mut self, report: &StatsReport) -> Result<(), PanelError> {
+        self.check_auth()?;
         let response = self
             .client
             .post(&self.url)
             .headers(self.headers.clone())
-            .body(serde_json::to_string(&stats)?)
+            .body(serde_json::to_string(report)?)
+            .send()
+            .await?;
+
+        let body = self.success_body(response).await?;
+        info!("Stats response: {:?}", body);
+        Ok(())
+    }
+
+    pub async fn post_logs(&mut self, report: &ErrorLogReport) -> Result<(), PanelError> {
+        self.check_auth()?;
+        let url = match &self.logs_url {
+            Some(url) => url.clone(),
+            None => logs_url(&self.url)?,
+        };
+
+        let response = self
+            .client
+            .post(url)
+            .headers(self.headers.clone())
+            .body(serde_json::to_string(report)?)
             .send()
             .await?;
 
-        match response.status().is_success() {
-            true => {
-                info!("Stats response: {:?}", response.text().await?);
-                Ok(())
+        self.success_body(response).await?;
+        Ok(())
+    }
+}
+
+/// The token as the value of the auth header.
+pub fn auth_header(token: &str) -> Result<HeaderValue, PanelError> {
+    HeaderValue::from_str(token).map_err(|_| PanelError::InvalidToken)
+}
+
+fn is_auth_status(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+}
+
+/// ` [name: value, ...]`, empty without headers.
+fn format_headers(headers: &[(String, String)]) -> String {
+    if headers.is_empty() {
+        return String::new();
+    }
+    let headers: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect();
+    format!(" [{}]", headers.join(", "))
+}
+
+/// The start of an error response's body, reading no more of it than
+/// `truncate_body` keeps.
+async fn error_body(mut response: Response) -> Result<String, reqwest::Error> {
+    let mut body = Vec::new();
+    while body.len() <= ERROR_BODY_LIMIT {
+        let Some(chunk) = response.chunk().await? else {
+            break;
+        };
+        body.extend_from_slice(&chunk);
+    }
+    Ok(truncate_body(&body))
+}
+
+/// Cuts `body` to `ERROR_BODY_LIMIT` bytes without splitting a char, noting
+/// that the rest was dropped.
+fn truncate_body(body: &[u8]) -> String {
+    if body.is_empty() {
+        return "(empty body)".to_string();
+    }
+    if body.len() <= ERROR_BODY_LIMIT {
+        return String::from_utf8_lossy(body).into_owned();
+    }
+    let mut end = ERROR_BODY_LIMIT;
+    if let Err(e) = std::str::from_utf8(&body[..end])
+        && e.error_len().is_none()
+    {
+        end = e.valid_up_to();
+    }
+    format!("{}... (truncated)", String::from_utf8_lossy(&body[..end]))
+}
+
+/// Appends a `logs` segment to the path of `url`, keeping its query.
+fn logs_url(url: &str) -> Result<String, PanelError> {
+    let invalid = |reason: String| PanelError::Url {
+        url: url.to_string(),
+        reason,
+    };
+
+    let mut parsed = Url::parse(url).map_err(|e| invalid(e.to_string()))?;
+    parsed
+        .path_segments_mut()
+        .map_err(|_| invalid("it cannot have a path".to_string()))?
+        .pop_if_empty()
+        .push("logs");
+    Ok(parsed.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::panel::{MockPanel, config_response};
+    use serde_json::json;
+
+    #[test]
+    fn test_logs_url() {
+        assert_eq!(
+            logs_url("http://localhost:3000/api/provider/proxy?id=abc").unwrap(),
+            "http://localhost:3000/api/provider/proxy/logs?id=abc"
+        );
+        assert_eq!(
+            logs_url("https://panel.example/proxy/").unwrap(),
+            "https://panel.example/proxy/logs"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_reports() {
+        let panel = MockPanel::start(config_response(8388)).await;
+        let mut fetch = panel.fetch();
+
+        fetch
+            .post_stats(&StatsReport {
+                stats: StatsFormatResponse::default(),
+                config_diffs: Vec::new(),
+                core_version: None,
+                config_error: None,
+            })
+            .await
+            .unwrap();
+        fetch
+            .post_logs(&ErrorLogReport {
+                entries: Vec::new(),
+                dropped: 3,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(panel.reports(), vec![json!({ "server": [], "user": [] })]);
+        assert_eq!(panel.logs(), vec![json!({ "entries": [], "dropped": 3 })]);
+    }
+
+    #[test]
+    fn test_invalid_token() {
+        assert!(matches!(
+            ServerFetch::new("http://panel".to_string(), "token\n".to_string()),
+            Err(PanelError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure() {
+        let panel = MockPanel::start(config_response(8388)).await;
+        let mut fetch = ServerFetch::new(panel.url.clone(), "wrong-token".to_string()).unwrap();
+
+        match fetch.get_config().await {
+            Err(ConfigError::Panel(PanelError::Status {
+                status,
+                headers,
+                body,
+            })) => {
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+                assert!(headers.contains(&("www-authenticate".to_string(), "Token".to_string())));
+                assert_eq!(body, "invalid token");
             }
-            false => Err("Error posting stats".into()),
+            other => panic!("expected a 401, got {:?}", other),
         }
+        assert!(fetch.auth_failure().is_some());
+
+        // Clones back off too, without sending anything
+        let report = StatsReport {
+            stats: StatsFormatResponse::default(),
+            config_diffs: Vec::new(),
+            core_version: None,
+            config_error: None,
+        };
+        let error = fetch.clone().post_stats(&report).await.unwrap_err();
+        assert!(matches!(error, PanelError::AuthBackoff { .. }));
+        assert!(error.is_auth_failure());
+        assert_eq!(panel.unauthorized(), 1);
+    }
+
+    #[test]
+    fn test_truncate_body() {
+        assert_eq!(truncate_body(b""), "(empty body)");
+        assert_eq!(truncate_body(b"denied"), "denied");
+
+        // The last char that fits is cut in half
+        let body = format!("a{}", "é".repeat(ERROR_BODY_LIMIT));
+        assert_eq!(
+            truncate_body(body.as_bytes()),
+            format!("a{}... (truncated)", "é".repeat(ERROR_BODY_LIMIT / 2 - 1))
+        );
     }
 }
@@ -1,20 +1,62 @@
-use anyhow::Result;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tonic::client::Grpc;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
 use tonic::transport::Channel;
-use v2rayapi::QueryStatsRequest;
-use v2rayapi::stats_service_client::StatsServiceClient;
+use v2rayapi::{QueryStatsRequest, QueryStatsResponse};
 
 pub mod v2rayapi {
     include!("../proto-gen/v2ray.core.app.stats.command.rs");
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+/// Failure to reach or query the core's `StatsService`.
+#[derive(Debug, Error)]
+pub enum StatsError {
+    #[error("invalid stats API endpoint {endpoint}: {reason}")]
+    Endpoint { endpoint: String, reason: String },
+
+    #[error("stats API transport error: {0}")]
+    Transport(#[from] tonic::transport::Error),
+
+    #[error("stats query failed: {}", .0.message())]
+    Query(#[from] tonic::Status),
+
+    #[error("stats API client not connected")]
+    NotConnected,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct StatsFormatResponse {
     server: Vec<ServerStats>,
     user: Vec<UserStats>,
 }
 
+impl StatsFormatResponse {
+    /// Adds the traffic in `other` to this one's.
+    pub fn merge(&mut self, other: StatsFormatResponse) {
+        for stats in other.server {
+            match self.server.iter_mut().find(|s| s.id == stats.id) {
+                Some(existing) => {
+                    existing.uplink += stats.uplink;
+                    existing.download += stats.download;
+                }
+                None => self.server.push(stats),
+            }
+        }
+        for stats in other.user {
+            match self.user.iter_mut().find(|u| u.user == stats.user) {
+                Some(existing) => {
+                    existing.uplink += stats.uplink;
+                    existing.download += stats.download;
+                }
+                None => self.user.push(stats),
+            }
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct ServerStats {
     pub id: String,
@@ -31,26 +73,39 @@ pub struct UserStats {
 
 #[derive(Clone, Debug)]
 pub struct V2rayApi {
-    client: Option<StatsServiceClient<Channel>>,
+    client: Option<Grpc<Channel>>,
+    /// Protobuf package of the service, Xray serves the same messages as
+    /// v2fly under its own.
+    package: String,
 }
 
 impl V2rayApi {
-    pub async fn new(url: impl Into<String>) -> Result<Self> {
-        let client = match StatsServiceClient::connect(url.into()).await {
-            Ok(client) => client,
-            Err(e) => return Err(e.into()),
-        };
+    pub async fn new(url: impl Into<String>, package: &str) -> Result<Self, StatsError> {
+        let url = url.into();
+        let channel = Channel::from_shared(url.clone())
+            .map_err(|e| StatsError::Endpoint {
+                endpoint: url,
+                reason: e.to_string(),
+            })?
+            .connect()
+            .await?;
 
         Ok(Self {
-            client: Some(client),
+            client: Some(Grpc::new(channel)),
+            package: package.to_string(),
         })
     }
 
-    pub async fn query_all_stats(&mut self, reset: bool) -> Result<StatsFormatResponse> {
-        let client = self
-            .client
-            .as_mut()
-            .ok_or_else(|| anyhow::anyhow!("Client not initialized"))?;
+    pub async fn query_all_stats(
+        &mut self,
+        reset: bool,
+    ) -> Result<StatsFormatResponse, StatsError> {
+        let path = format!("/{}.StatsService/QueryStats", self.package);
+        let path = PathAndQuery::try_from(path.as_str()).map_err(|e| StatsError::Endpoint {
+            endpoint: path.clone(),
+            reason: e.to_string(),
+        })?;
+        let client = self.client.as_mut().ok_or(StatsError::NotConnected)?;
 
         let req = tonic::Request::new(QueryStatsRequest {
             pattern: String::new(),
@@ -59,7 +114,9 @@ impl V2rayApi {
             reset,
         });
 
-        let res = client.query_stats(req).await?;
+        client.ready().await?;
+        let res: tonic::Response<QueryStatsResponse> =
+            client.unary(req, path, ProstCodec::default()).await?;
         let stats_list = &res.get_ref().stat;
 
         let server_regex = Regex::new(r"^inbound>>>[^>]+>>>traffic>>>(uplink|downlink)$").unwrap();
@@ -0,0 +1,322 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+
+use super::sing_box::{SingBoxConfig, shadowsocks::ShadowsocksInbound};
+
+/// How far-reaching a configuration update is for sing-box.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Only the ordering of inbounds or users changed.
+    Cosmetic,
+    /// Only user lists changed.
+    UserOnly,
+    Structural,
+}
+
+impl ChangeKind {
+    /// Classifies the update from `old` to `new`, `None` when they are identical.
+    pub fn between(old: &SingBoxConfig, new: &SingBoxConfig) -> Option<Self> {
+        if old == new {
+            return None;
+        }
+
+        let (old, new) = (old.normalized(), new.normalized());
+
+        if old == new {
+            Some(ChangeKind::Cosmetic)
+        } else if old.without_users() == new.without_users() {
+            Some(ChangeKind::UserOnly)
+        } else {
+            Some(ChangeKind::Structural)
+        }
+    }
+}
+
+/// Structured difference between two runtime configurations.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigDiff {
+    pub inbounds_added: Vec<String>,
+    pub inbounds_removed: Vec<String>,
+    /// Inbounds whose listen settings changed, ignoring their users.
+    pub inbounds_modified: Vec<String>,
+    pub users: Vec<InboundUsersDiff>,
+    pub outbounds_changed: bool,
+    pub route_changed: bool,
+    pub dns_changed: bool,
+    pub log_changed: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InboundUsersDiff {
+    pub inbound: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Users whose password changed.
+    pub modified: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn between(old: &SingBoxConfig, new: &SingBoxConfig) -> Self {
+        let old_inbounds: HashMap<&str, &ShadowsocksInbound> =
+            old.inbounds.iter().map(|i| (i.tag.as_str(), i)).collect();
+        let new_inbounds: HashMap<&str, &ShadowsocksInbound> =
+            new.inbounds.iter().map(|i| (i.tag.as_str(), i)).collect();
+
+        let mut diff = ConfigDiff {
+            outbounds_changed: old.outbounds != new.outbounds,
+            route_changed: old.route != new.route,
+            dns_changed: old.dns != new.dns,
+            log_changed: old.log != new.log,
+            ..Default::default()
+        };
+
+        for inbound in &new.inbounds {
+            match old_inbounds.get(inbound.tag.as_str()) {
+                None => diff.inbounds_added.push(inbound.tag.clone()),
+                Some(old_inbound) => {
+                    if !same_listen(old_inbound, inbound) {
+                        diff.inbounds_modified.push(inbound.tag.clone());
+                    }
+
+                    let users = users_diff(old_inbound, inbound);
+                    if !users.is_empty() {
+                        diff.users.push(users);
+                    }
+                }
+            }
+        }
+
+        for inbound in &old.inbounds {
+            if !new_inbounds.contains_key(inbound.tag.as_str()) {
+                diff.inbounds_removed.push(inbound.tag.clone());
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &ConfigDiff::default()
+    }
+
+    /// Whether any user lost access or had the password changed.
+    pub fn revokes_users(&self) -> bool {
+        self.users
+            .iter()
+            .any(|u| !u.removed.is_empty() || !u.modified.is_empty())
+    }
+}
+
+impl InboundUsersDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Compares everything but the user list of two inbounds.
+fn same_listen(old: &ShadowsocksInbound, new: &ShadowsocksInbound) -> bool {
+    ShadowsocksInbound {
+        users: None,
+        ..old.clone()
+    } == ShadowsocksInbound {
+        users: None,
+        ..new.clone()
+    }
+}
+
+fn users_diff(old: &ShadowsocksInbound, new: &ShadowsocksInbound) -> InboundUsersDiff {
+    let old_users: HashMap<&str, &str> = old
+        .users
+        .iter()
+        .flatten()
+        .map(|u| (u.name.as_str(), u.password.as_str()))
+        .collect();
+    let new_users: HashMap<&str, &str> = new
+        .users
+        .iter()
+        .flatten()
+        .map(|u| (u.name.as_str(), u.password.as_str()))
+        .collect();
+
+    let mut diff = InboundUsersDiff {
+        inbound: new.tag.clone(),
+        ..Default::default()
+    };
+
+    for user in new.users.iter().flatten() {
+        match old_users.get(user.name.as_str()) {
+            None => diff.added.push(user.name.clone()),
+            Some(password) if *password != user.password => diff.modified.push(user.name.clone()),
+            Some(_) => {}
+        }
+    }
+
+    for user in old.users.iter().flatten() {
+        if !new_users.contains_key(user.name.as_str()) {
+            diff.removed.push(user.name.clone());
+        }
+    }
+
+    diff
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes");
+        }
+
+        let mut parts = Vec::new();
+
+        if !self.inbounds_added.is_empty() {
+            parts.push(format!(
+                "inbounds added: {}",
+                self.inbounds_added.join(", ")
+            ));
+        }
+        if !self.inbounds_removed.is_empty() {
+            parts.push(format!(
+                "inbounds removed: {}",
+                self.inbounds_removed.join(", ")
+            ));
+        }
+        if !self.inbounds_modified.is_empty() {
+            parts.push(format!(
+                "inbounds modified: {}",
+                self.inbounds_modified.join(", ")
+            ));
+        }
+        for users in &self.users {
+            let mut changes = Vec::new();
+            if !users.added.is_empty() {
+                changes.push(format!("+{}", users.added.join(", +")));
+            }
+            if !users.removed.is_empty() {
+                changes.push(format!("-{}", users.removed.join(", -")));
+            }
+            if !users.modified.is_empty() {
+                changes.push(format!("~{}", users.modified.join(", ~")));
+            }
+            parts.push(format!(
+                "inbound {} users: {}",
+                users.inbound,
+                changes.join(", ")
+            ));
+        }
+        if self.outbounds_changed {
+            parts.push("outbounds changed".to_string());
+        }
+        if self.route_changed {
+            parts.push("route changed".to_string());
+        }
+        if self.dns_changed {
+            parts.push("dns changed".to_string());
+        }
+        if self.log_changed {
+            parts.push("log changed".to_string());
+        }
+
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(users: &[(&str, &str)]) -> SingBoxConfig {
+        serde_json::from_value(serde_json::json!({
+            "log": { "level": "info" },
+            "dns": { "servers": [], "rules": [] },
+            "outbounds": [{ "type": "direct", "tag": "direct" }],
+            "route": { "rules": [] },
+            "inbounds": [{
+                "type": "shadowsocks",
+                "tag": "ss-in",
+                "listen": "::",
+                "listen_port": 8388,
+                "network": null,
+                "method": "2022-blake3-aes-128-gcm",
+                "password": "server",
+                "users": users
+                    .iter()
+                    .map(|(name, password)| serde_json::json!({ "name": name, "password": password }))
+                    .collect::<Vec<_>>(),
+            }],
+            "experimental": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_diff_users() {
+        let old = config(&[("alice", "a"), ("bob", "b")]);
+        let new = config(&[("bob", "b2"), ("carol", "c")]);
+
+        let diff = ConfigDiff::between(&old, &new);
+
+        assert_eq!(
+            diff.users,
+            vec![InboundUsersDiff {
+                inbound: "ss-in".to_string(),
+                added: vec!["carol".to_string()],
+                removed: vec!["alice".to_string()],
+                modified: vec!["bob".to_string()],
+            }]
+        );
+        assert!(diff.inbounds_modified.is_empty());
+        assert_eq!(
+            diff.to_string(),
+            "inbound ss-in users: +carol, -alice, ~bob"
+        );
+    }
+
+    #[test]
+    fn test_diff_inbounds() {
+        let old = config(&[]);
+        let mut new = config(&[]);
+        new.inbounds[0].listen_port = 8389;
+        new.inbounds.push(ShadowsocksInbound {
+            tag: "ss-in-2".to_string(),
+            ..new.inbounds[0].clone()
+        });
+        new.outbounds.push(new.outbounds[0].clone());
+
+        let diff = ConfigDiff::between(&old, &new);
+
+        assert_eq!(diff.inbounds_added, vec!["ss-in-2"]);
+        assert_eq!(diff.inbounds_modified, vec!["ss-in"]);
+        assert!(diff.outbounds_changed);
+        assert!(!diff.route_changed);
+        assert!(ConfigDiff::between(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_change_kind() {
+        let old = config(&[("alice", "a"), ("bob", "b")]);
+
+        assert_eq!(ChangeKind::between(&old, &old), None);
+
+        let reordered = config(&[("bob", "b"), ("alice", "a")]);
+        assert_eq!(
+            ChangeKind::between(&old, &reordered),
+            Some(ChangeKind::Cosmetic)
+        );
+
+        let users = config(&[("alice", "a")]);
+        assert_eq!(
+            ChangeKind::between(&old, &users),
+            Some(ChangeKind::UserOnly)
+        );
+
+        let mut structural = users.clone();
+        structural.inbounds[0].listen_port = 8389;
+        assert_eq!(
+            ChangeKind::between(&old, &structural),
+            Some(ChangeKind::Structural)
+        );
+    }
+}
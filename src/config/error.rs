@@ -0,0 +1,56 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::api::server::PanelError;
+
+/// Failure to fetch, check or write the config of a node.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    Panel(#[from] PanelError),
+
+    /// The panel's response doesn't match `ConfigResponse`.
+    #[error("invalid config from the panel at `{path}`: {source}")]
+    Parse {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// The config is well-formed but can't be run.
+    #[error("invalid config at `{path}`: {reason}")]
+    Invalid { path: String, reason: String },
+
+    #[error("configuration not fetched")]
+    NotFetched,
+
+    #[error("failed to serialize the core config: {0}")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error("failed to write {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl ConfigError {
+    pub(crate) fn invalid(path: impl Into<String>, reason: impl Into<String>) -> Self {
+        ConfigError::Invalid {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Whether the panel rejected the token, see `PanelError::is_auth_failure`.
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(self, ConfigError::Panel(e) if e.is_auth_failure())
+    }
+
+    pub(crate) fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| ConfigError::Io { path, source }
+    }
+}
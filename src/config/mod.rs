@@ -1,15 +1,29 @@
+use diff::{ChangeKind, ConfigDiff};
 use serde::{Deserialize, Serialize};
 use sing_box::{
     SingBoxConfig,
     experimental::{Experimental, V2rayApi, V2rayApiStats},
 };
-use std::{error::Error, path::PathBuf};
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use temp_dir::TempDir;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::api::server::ServerFetch;
+use crate::core::{Core, CoreKind};
+use crate::process::privileges::Credentials;
+use crate::process::version::{REUSE_ADDR_SINCE, SingBoxVersion, TAG_V2RAY_API};
+
+pub use error::ConfigError;
 
-mod sing_box;
+pub mod diff;
+pub mod error;
+pub mod sing_box;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ConfigResponse {
@@ -17,6 +31,49 @@ pub struct ConfigResponse {
 
     #[serde(rename = "guardConfig")]
     pub guard_config: GuardConfig,
+
+    /// sing-box release the node should run, managed by the pod when set.
+    pub core: Option<CoreRelease>,
+
+    /// Proxy core the node runs, `runtime` is translated to its config.
+    #[serde(rename = "coreType", default)]
+    pub core_type: CoreKind,
+}
+
+impl ConfigResponse {
+    /// Parses a panel response, pointing at the offending field on errors.
+    pub fn parse(body: &str) -> Result<Self, ConfigError> {
+        let deserializer = &mut serde_json::Deserializer::from_str(body);
+        let response: Self =
+            serde_path_to_error::deserialize(deserializer).map_err(|e| ConfigError::Parse {
+                path: e.path().to_string(),
+                source: e.into_inner(),
+            })?;
+        response.validate()?;
+        Ok(response)
+    }
+
+    /// Checks what the types can't express.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.guard_config.reporting_cycle == 0 {
+            return Err(ConfigError::invalid(
+                "guardConfig.reportingCycle",
+                "must be at least 1 second",
+            ));
+        }
+
+        let mut tags = HashSet::new();
+        for (i, inbound) in self.runtime.inbounds.iter().enumerate() {
+            if !tags.insert(inbound.tag.as_str()) {
+                return Err(ConfigError::invalid(
+                    format!("runtime.inbounds[{}].tag", i),
+                    format!("duplicate tag `{}`", inbound.tag),
+                ));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -25,90 +82,261 @@ pub struct GuardConfig {
     pub reporting_cycle: u64,
 }
 
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CoreRelease {
+    pub version: String,
+
+    /// tar.gz release archive, an http(s) URL or a local path.
+    pub url: String,
+
+    /// Hex encoded SHA-256 of the archive.
+    pub sha256: String,
+
+    /// minisign signature of the archive.
+    pub signature: Option<String>,
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum FetchStatus {
-    Updated,
+    Updated(ChangeKind),
     Unchanged,
     Error(String),
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct ConfigOptions {
+    /// Directory holding the runtime config, an anonymous temp dir when unset.
+    pub runtime_dir: Option<PathBuf>,
+
+    /// Forces the sing-box log level regardless of the panel.
+    pub log_level: Option<String>,
+
+    /// Clears `log.output` so sing-box logs to stderr, where the pod captures it.
+    pub capture_log: bool,
+
+    /// Points `log.output` at this FIFO, read by the process manager.
+    pub log_pipe: Option<PathBuf>,
+
+    /// Installed sing-box, features it was built without are left out of the config.
+    pub core_version: Option<SingBoxVersion>,
+
+    /// User sing-box runs as, given the runtime dir and config.
+    pub owner: Option<Credentials>,
+
+    /// Lets every inbound share its port with another sing-box, required by
+    /// `ProcessManager::blue_green_restart`.
+    pub reuse_addr: bool,
+
+    /// Listen address of the v2ray API, a free localhost port when unset. An
+    /// adopted sing-box only serves stats on the one it was started with.
+    pub v2ray_api_listen: Option<String>,
+}
+
+/// Name of the runtime config in the runtime dir.
+pub const RUNTIME_FILE: &str = "singbox-runtime.json";
+
+/// Diffs kept while the panel can't be reached, the oldest are dropped first.
+const MAX_PENDING_DIFFS: usize = 32;
+
 pub struct ConfigManager {
     pub fetch: ServerFetch,
 
     pub config: Option<ConfigResponse>,
 
+    options: ConfigOptions,
+
     #[allow(dead_code)]
-    temp_dir: TempDir,
+    temp_dir: Option<TempDir>,
 
     pub runtime_path: PathBuf,
 
     pub v2ray_api_endpoint: String,
 
     pub fetch_status: Option<FetchStatus>,
+
+    /// Diffs of applied updates not yet reported to the panel, at most
+    /// `MAX_PENDING_DIFFS`.
+    pub pending_diffs: Vec<ConfigDiff>,
+
+    /// Diff of the update applied by the last fetch.
+    pub last_diff: Option<ConfigDiff>,
+
+    /// Core chosen by the panel on the first fetch.
+    core: Option<Arc<dyn Core>>,
 }
 
 impl ConfigManager {
-    pub async fn new(fetch: ServerFetch) -> Self {
-        let temp_dir = TempDir::new().unwrap();
+    pub async fn new(fetch: ServerFetch, options: ConfigOptions) -> Result<Self, ConfigError> {
+        let owner = options.owner.as_ref();
+        let (temp_dir, runtime_dir) = match options.runtime_dir.clone() {
+            Some(dir) => {
+                create_private_dir(&dir, owner).map_err(ConfigError::io(&dir))?;
+                (None, dir)
+            }
+            None => {
+                let temp_dir = TempDir::new().map_err(ConfigError::io(std::env::temp_dir()))?;
+                let dir = temp_dir.path().to_path_buf();
+                make_private(&dir, owner).map_err(ConfigError::io(&dir))?;
+                (Some(temp_dir), dir)
+            }
+        };
 
-        let runtime_path = temp_dir.child("singbox-runtime.json");
+        let runtime_path = runtime_dir.join(RUNTIME_FILE);
 
-        let port = portpicker::pick_unused_port().expect("No ports free");
+        let v2ray_api_endpoint = options.v2ray_api_listen.clone().unwrap_or_else(|| {
+            let port = portpicker::pick_unused_port().expect("No ports free");
+            format!("localhost:{}", port)
+        });
 
         let mut config = Self {
             fetch,
             config: None,
+            options,
             temp_dir,
             runtime_path,
-            v2ray_api_endpoint: format!("localhost:{}", port),
+            v2ray_api_endpoint,
             fetch_status: None,
+            pending_diffs: Vec::new(),
+            last_diff: None,
+            core: None,
         };
-        config.fetch().await.unwrap();
+        config.fetch().await?;
 
-        config
+        Ok(config)
     }
 
-    pub async fn fetch(&mut self) -> Result<(), Box<dyn Error>> {
-        let response = self.fetch.get_config().await?;
+    pub async fn fetch(&mut self) -> Result<(), ConfigError> {
+        self.fetch_status = None;
+        self.last_diff = None;
 
-        self.config = Some(response);
+        let mut response = match self.fetch.get_config().await {
+            Ok(response) => response,
+            Err(e) => {
+                self.fetch_status = Some(FetchStatus::Error(e.to_string()));
+                return Err(e);
+            }
+        };
 
-        let _ = self.prepare();
+        // The process manager is set up for the first core, switching needs a new pod
+        let core = self
+            .core
+            .get_or_insert_with(|| response.core_type.core())
+            .clone();
+        if response.core_type != core.kind() {
+            warn!(
+                "The panel switched the core to {}, restart the pod to apply it",
+                response.core_type
+            );
+        }
 
-        let new_runtime_str = serde_json::to_string(&self.config.as_ref().unwrap().runtime)?;
+        self.prepare(&mut response);
 
-        if let Ok(old_runtime_str) = std::fs::read_to_string(&self.runtime_path) {
-            if old_runtime_str == new_runtime_str {
-                self.fetch_status = Some(FetchStatus::Unchanged);
-                info!("Runtime configuration unchanged, skipping save.");
-                return Ok(());
-            }
+        // `config` holds the last written runtime, a failed write is retried
+        let old_runtime = match &self.config {
+            Some(old_config) => Some(old_config.runtime.clone()),
+            None => self.read_runtime(),
+        };
+        let new_runtime = &response.runtime;
+
+        let change = match &old_runtime {
+            Some(old_runtime) => ChangeKind::between(old_runtime, new_runtime),
+            None => Some(ChangeKind::Structural),
+        };
+
+        let Some(change) = change else {
+            self.config = Some(response);
+            self.fetch_status = Some(FetchStatus::Unchanged);
+            info!("Runtime configuration unchanged, skipping save.");
+            return Ok(());
+        };
+
+        let diff = old_runtime.map(|old_runtime| ConfigDiff::between(&old_runtime, new_runtime));
+        if let Err(e) = self.write(core.as_ref(), new_runtime) {
+            self.fetch_status = Some(FetchStatus::Error(e.to_string()));
+            error!("Failed to update runtime configuration: {}", e);
+            return Err(e);
         }
 
-        match std::fs::write(&self.runtime_path, new_runtime_str) {
-            Ok(_) => {
-                self.fetch_status = Some(FetchStatus::Updated);
-                info!(
-                    "Runtime configuration successfully saved to: {}",
-                    self.runtime_path.display()
-                );
-            }
-            Err(e) => {
-                self.fetch_status = Some(FetchStatus::Error(e.to_string()));
-                error!("Failed to update runtime configuration: {}", e);
+        self.config = Some(response);
+        self.fetch_status = Some(FetchStatus::Updated(change));
+        info!(
+            "Runtime configuration successfully saved to: {} ({:?} change)",
+            self.runtime_path.display(),
+            change
+        );
+
+        if let Some(diff) = diff {
+            info!("Runtime configuration changes: {}", diff);
+            if self.pending_diffs.len() >= MAX_PENDING_DIFFS {
+                warn!("Dropping the oldest unreported config diff");
+                self.pending_diffs.remove(0);
             }
+            self.pending_diffs.push(diff.clone());
+            self.last_diff = Some(diff);
         }
 
         Ok(())
     }
 
-    fn prepare(&mut self) -> Result<(), Box<dyn Error>> {
-        if self.config.is_none() {
-            return Err("Configuration not fetched".into());
+    /// Prepares the current config again and rewrites the runtime config, for
+    /// when an option it depends on changed.
+    pub fn rebuild(&mut self) -> Result<(), ConfigError> {
+        let mut config = self.config.clone().ok_or(ConfigError::NotFetched)?;
+        self.prepare(&mut config);
+
+        self.write(self.core().as_ref(), &config.runtime)?;
+        self.config = Some(config);
+        Ok(())
+    }
+
+    /// Writes `runtime` as the config of `core`.
+    fn write(&self, core: &dyn Core, runtime: &SingBoxConfig) -> Result<(), ConfigError> {
+        let contents = core.build_config(runtime)?;
+        write_atomic(&self.runtime_path, &contents, self.options.owner.as_ref())
+            .map_err(ConfigError::io(&self.runtime_path))
+    }
+
+    /// Core the runtime config is written for.
+    pub fn core(&self) -> Arc<dyn Core> {
+        self.core
+            .clone()
+            .unwrap_or_else(|| CoreKind::default().core())
+    }
+
+    /// Reads the runtime config left by a previous fetch or pod run.
+    fn read_runtime(&self) -> Option<SingBoxConfig> {
+        let runtime_str = fs::read_to_string(&self.runtime_path).ok()?;
+        serde_json::from_str(&runtime_str).ok()
+    }
+
+    /// Applies the pod's options to a config from the panel.
+    fn prepare(&self, config: &mut ConfigResponse) {
+        let stats_enabled = self.stats_enabled();
+        let runtime = &mut config.runtime;
+
+        if let Some(level) = &self.options.log_level {
+            runtime.log.level = level.clone();
+        }
+        if let Some(log_pipe) = &self.options.log_pipe {
+            runtime.log.output = Some(log_pipe.to_string_lossy().to_string());
+        } else if self.options.capture_log {
+            runtime.log.output = None;
         }
 
-        let runtime = &mut self.config.as_mut().unwrap().runtime;
+        // A rebuild for a sing-box without it takes the field out again
+        if self.options.reuse_addr {
+            let reuse_addr = self.reuse_addr_enabled().then_some(true);
+            for inbound in &mut runtime.inbounds {
+                inbound.reuse_addr = reuse_addr;
+            }
+        }
+
+        if !stats_enabled {
+            runtime.experimental = None;
+            return;
+        }
 
         // prepare v2ray api
         runtime.experimental = Some(Experimental {
@@ -130,42 +358,379 @@ impl ConfigManager {
                 },
             },
         });
+    }
 
-        Ok(())
+    /// Whether the v2ray api stats service is configured, assumed when the
+    /// sing-box version is unknown.
+    pub fn stats_enabled(&self) -> bool {
+        self.options
+            .core_version
+            .as_ref()
+            .is_none_or(|version| version.has_tag(TAG_V2RAY_API))
     }
+
+    /// Whether inbounds are written with `reuse_addr`, which older sing-box
+    /// versions reject. Assumed supported when the version is unknown.
+    pub fn reuse_addr_enabled(&self) -> bool {
+        self.options.reuse_addr
+            && self
+                .options
+                .core_version
+                .as_ref()
+                .is_none_or(|version| version.at_least(REUSE_ADDR_SINCE))
+    }
+
+    /// Passwords of the current config, kept out of logs sent to the panel.
+    pub fn secrets(&self) -> Vec<String> {
+        self.config
+            .as_ref()
+            .map(|config| config.runtime.passwords())
+            .unwrap_or_default()
+    }
+
+    pub fn core_version(&self) -> Option<&SingBoxVersion> {
+        self.options.core_version.as_ref()
+    }
+
+    pub fn set_core_version(&mut self, version: SingBoxVersion) {
+        self.options.core_version = Some(version);
+    }
+}
+
+/// Creates `dir` readable by the pod and `owner` only. An existing directory
+/// is only taken over when it's already private to the pod, the pod must not
+/// chmod or chown a directory such as /tmp it was merely pointed at.
+fn create_private_dir(dir: &Path, owner: Option<&Credentials>) -> io::Result<()> {
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::create_dir(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => check_private_dir(dir, owner)?,
+        Err(e) => return Err(e),
+    }
+
+    make_private(dir, owner)
+}
+
+/// Restricts a directory the pod created to the pod. The dir stays the pod's,
+/// `owner` only gets to read it through its group, so sing-box can't replace
+/// the pid file the pod later trusts.
+fn make_private(dir: &Path, owner: Option<&Credentials>) -> io::Result<()> {
+    if let Some(owner) = owner {
+        owner.chgrp(dir)?;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = if owner.is_some() { 0o750 } else { 0o700 };
+        fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(())
+}
+
+/// Fails unless the existing `dir` is a directory of the pod that only the
+/// group of `owner` may read besides it.
+#[cfg(unix)]
+fn check_private_dir(dir: &Path, owner: Option<&Credentials>) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::symlink_metadata(dir)?;
+    let mode = metadata.mode();
+    let owned = metadata.uid() == nix::unistd::geteuid().as_raw();
+    let group_ok =
+        mode & 0o070 == 0 || (mode & 0o020 == 0 && owner.is_some_and(|o| o.gid == metadata.gid()));
+    if !metadata.is_dir() || !owned || !group_ok || mode & 0o007 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is not a private directory of the pod, pass a new path or chmod it to 0700",
+                dir.display()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private_dir(dir: &Path, _owner: Option<&Credentials>) -> io::Result<()> {
+    match dir.is_dir() {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is not a directory", dir.display()),
+        )),
+    }
+}
+
+/// Writes `contents` to a sibling temp file and renames it over `path`, so
+/// sing-box never reads a half-written config. The file holds every user
+/// password, hence the 0600 mode, or 0640 readable by the group of `owner`
+/// when sing-box runs as another user.
+pub(crate) fn write_atomic(
+    path: &Path,
+    contents: &[u8],
+    owner: Option<&Credentials>,
+) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    let mode = if owner.is_some() { 0o640 } else { 0o600 };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path)?;
+
+    if let Some(owner) = owner {
+        owner.chgrp(&tmp_path)?;
+    }
+
+    // mode() only applies on creation, a stale temp file may be left over
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+    }
+
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+
+    // persist the rename itself
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::server::PanelError;
+    use crate::testing::panel::{Fault, MockPanel, config_response};
+    use axum::http::StatusCode;
+    use std::time::Duration;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let runtime_dir = dir.child("pod").join("runtime");
+        create_private_dir(&runtime_dir, None).unwrap();
+        assert_eq!(mode(&runtime_dir), 0o700);
+        // A later pod instance takes over its own dir
+        create_private_dir(&runtime_dir, None).unwrap();
+
+        // but leaves a shared one alone
+        let shared = dir.child("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        let e = create_private_dir(&shared, None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(mode(&shared), 0o755);
+
+        fs::write(dir.child("file"), b"").unwrap();
+        assert!(create_private_dir(&dir.child("file"), None).is_err());
+
+        // sing-box only gets to read through its group
+        let owner = Credentials {
+            uid: nix::unistd::geteuid().as_raw(),
+            gid: nix::unistd::getegid().as_raw(),
+        };
+        let core_dir = dir.child("core");
+        create_private_dir(&core_dir, Some(&owner)).unwrap();
+        assert_eq!(mode(&core_dir), 0o750);
+        create_private_dir(&core_dir, Some(&owner)).unwrap();
+        assert!(create_private_dir(&core_dir, None).is_err());
+
+        let config = core_dir.join(RUNTIME_FILE);
+        write_atomic(&config, b"{}", Some(&owner)).unwrap();
+        assert_eq!(mode(&config), 0o640);
+
+        fs::set_permissions(&core_dir, fs::Permissions::from_mode(0o770)).unwrap();
+        assert!(create_private_dir(&core_dir, Some(&owner)).is_err());
+    }
 
-    #[allow(dead_code)]
-    async fn setup_test_config() -> ConfigManager {
-        let fetch = ServerFetch::new(
-            "http://localhost:3000/api/provider/proxy?id=cm4ivp4i50004usi8fkq2uffq".to_string(),
-            "OmutiAkm7eW2W1m3XradC1/rO41JzoFk0Vt6f7mFvFQEUJMovBGOIv+3Hr9fB3yVwKhJqSk=".to_string(),
-        );
-
-        ConfigManager::new(fetch).await
+    async fn setup_test_config(panel: &MockPanel) -> ConfigManager {
+        ConfigManager::new(panel.fetch(), ConfigOptions::default())
+            .await
+            .unwrap()
     }
 
     #[tokio::test]
     async fn test_config_fetch() {
-        let config = setup_test_config().await;
-
-        println!("{:?}", config.config.as_ref().unwrap());
+        let panel = MockPanel::start(config_response(8388)).await;
+        let mut config = setup_test_config(&panel).await;
 
         assert!(config.config.is_some());
+        assert!(matches!(
+            config.fetch_status,
+            Some(FetchStatus::Updated(ChangeKind::Structural))
+        ));
+
+        config.fetch().await.unwrap();
+        assert!(matches!(config.fetch_status, Some(FetchStatus::Unchanged)));
+
+        panel.set_config(config_response(8389));
+        config.fetch().await.unwrap();
+        assert!(matches!(config.fetch_status, Some(FetchStatus::Updated(_))));
+        assert_eq!(config.pending_diffs.len(), 1);
+
+        // Unreported diffs don't pile up while posting fails
+        for port in (8390..).take(MAX_PENDING_DIFFS) {
+            panel.set_config(config_response(port));
+            config.fetch().await.unwrap();
+        }
+        assert_eq!(config.pending_diffs.len(), MAX_PENDING_DIFFS);
+        assert_eq!(config.pending_diffs.last(), config.last_diff.as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_config_write_retried() {
+        let panel = MockPanel::start(config_response(8388)).await;
+        let mut config = setup_test_config(&panel).await;
+        panel.set_config(config_response(8389));
+
+        // A directory in the way makes the rename fail
+        fs::remove_file(&config.runtime_path).unwrap();
+        fs::create_dir_all(config.runtime_path.join("blocker")).unwrap();
+        assert!(matches!(config.fetch().await, Err(ConfigError::Io { .. })));
+        assert!(matches!(config.fetch_status, Some(FetchStatus::Error(_))));
+        assert_eq!(
+            config.config.as_ref().unwrap().runtime.inbounds[0].listen_port,
+            8388
+        );
+
+        fs::remove_dir_all(&config.runtime_path).unwrap();
+        config.fetch().await.unwrap();
+        assert!(matches!(config.fetch_status, Some(FetchStatus::Updated(_))));
+        let runtime: serde_json::Value =
+            serde_json::from_slice(&fs::read(&config.runtime_path).unwrap()).unwrap();
+        assert_eq!(runtime["inbounds"][0]["listen_port"], 8389);
     }
 
     #[tokio::test]
     async fn test_config_file() {
-        let config = setup_test_config().await;
+        let panel = MockPanel::start(config_response(8388)).await;
+        let config = setup_test_config(&panel).await;
+
+        let runtime: serde_json::Value =
+            serde_json::from_slice(&fs::read(&config.runtime_path).unwrap()).unwrap();
+
+        assert_eq!(runtime["inbounds"][0]["listen_port"], 8388);
+        // Unset options are left out rather than written as null
+        assert_eq!(runtime["log"], serde_json::json!({ "level": "info" }));
+        assert_eq!(
+            runtime["experimental"]["v2ray_api"]["listen"],
+            config.v2ray_api_endpoint.as_str()
+        );
+        assert_eq!(
+            runtime["experimental"]["v2ray_api"]["stats"]["users"],
+            serde_json::json!(["alice"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reuse_addr() {
+        let panel = MockPanel::start(config_response(8388)).await;
+        let options = ConfigOptions {
+            reuse_addr: true,
+            ..Default::default()
+        };
+        let mut config = ConfigManager::new(panel.fetch(), options).await.unwrap();
+        let read = |config: &ConfigManager| -> serde_json::Value {
+            serde_json::from_slice(&fs::read(&config.runtime_path).unwrap()).unwrap()
+        };
+        assert_eq!(read(&config)["inbounds"][0]["reuse_addr"], true);
 
-        let runtime = std::fs::read_to_string(&config.runtime_path).unwrap();
+        // Older sing-box rejects the field
+        config.set_core_version(SingBoxVersion {
+            version: "1.11.15".to_string(),
+            tags: vec![TAG_V2RAY_API.to_string()],
+            ..Default::default()
+        });
+        config.rebuild().unwrap();
+        assert!(!config.reuse_addr_enabled());
+        assert!(read(&config)["inbounds"][0].get("reuse_addr").is_none());
+    }
 
-        println!("save path: {:?}", config.runtime_path);
-        println!("runtime config: {:?}", runtime);
+    #[tokio::test]
+    async fn test_config_fetch_errors() {
+        let panel = MockPanel::start(config_response(8388)).await;
+        let mut config = setup_test_config(&panel).await;
+        panel.set_config(config_response(8389));
+
+        panel.set_fault(Some(Fault::Status(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(matches!(
+            config.fetch().await,
+            Err(ConfigError::Panel(PanelError::Status {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                ..
+            }))
+        ));
+
+        panel.set_fault(Some(Fault::BadJson));
+        assert!(config.fetch().await.is_err());
+
+        panel.set_fault(Some(Fault::Delay(Duration::from_secs(10))));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), config.fetch())
+                .await
+                .is_err()
+        );
 
-        assert!(!runtime.is_empty());
+        panel.set_fault(None);
+        let mut response = config_response(8389);
+        response["guardConfig"]["reportingCycle"] = "soon".into();
+        panel.set_config(response.clone());
+        match config.fetch().await {
+            Err(ConfigError::Parse { path, .. }) => assert_eq!(path, "guardConfig.reportingCycle"),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert!(matches!(config.fetch_status, Some(FetchStatus::Error(_))));
+
+        response["guardConfig"]["reportingCycle"] = 0.into();
+        panel.set_config(response);
+        match config.fetch().await {
+            Err(ConfigError::Invalid { path, .. }) => {
+                assert_eq!(path, "guardConfig.reportingCycle")
+            }
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+        panel.set_config(config_response(8389));
+
+        // The last good config stays in place
+        assert_eq!(
+            config.config.as_ref().unwrap().runtime.inbounds[0].listen_port,
+            8388
+        );
+
+        panel.set_fault(None);
+        let mut unauthorized = ServerFetch::new(panel.url.clone(), "wrong".to_string()).unwrap();
+        assert!(unauthorized.get_config().await.is_err());
+        assert_eq!(panel.unauthorized(), 1);
+
+        config.fetch().await.unwrap();
+        assert!(matches!(config.fetch_status, Some(FetchStatus::Updated(_))));
     }
 }
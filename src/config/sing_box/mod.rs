@@ -7,7 +7,7 @@ use shadowsocks::ShadowsocksInbound;
 pub mod experimental;
 pub mod shadowsocks;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct SingBoxConfig {
     pub log: LogConfig,
     pub dns: DnsConfig,
@@ -17,42 +17,129 @@ pub struct SingBoxConfig {
     pub experimental: Option<Experimental>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+impl SingBoxConfig {
+    /// Returns a copy with the lists whose order sing-box ignores sorted.
+    /// Outbounds, route and dns rules keep their order since it is significant.
+    pub fn normalized(&self) -> Self {
+        let mut config = self.clone();
+
+        config.inbounds.sort_by(|a, b| a.tag.cmp(&b.tag));
+        for inbound in &mut config.inbounds {
+            if let Some(users) = &mut inbound.users {
+                users.sort_by(|a, b| a.name.cmp(&b.name));
+            }
+        }
+
+        if let Some(experimental) = &mut config.experimental {
+            let stats = &mut experimental.v2ray_api.stats;
+            stats.inbounds.sort();
+            stats.outbounds.sort();
+            stats.users.sort();
+        }
+
+        config
+    }
+
+    /// Returns a copy with every user list removed.
+    pub fn without_users(&self) -> Self {
+        let mut config = self.clone();
+
+        for inbound in &mut config.inbounds {
+            inbound.users = None;
+        }
+
+        if let Some(experimental) = &mut config.experimental {
+            experimental.v2ray_api.stats.users.clear();
+        }
+
+        config
+    }
+
+    /// TCP addresses sing-box accepts connections on once it is up: every
+    /// inbound that handles TCP and the v2ray API endpoint.
+    pub fn probe_addrs(&self) -> Vec<String> {
+        let mut addrs: Vec<String> = self
+            .inbounds
+            .iter()
+            .filter(|inbound| inbound.network.as_deref().is_none_or(|n| n == "tcp"))
+            .map(|inbound| local_addr(&inbound.listen, inbound.listen_port))
+            .collect();
+
+        if let Some(experimental) = &self.experimental {
+            addrs.push(experimental.v2ray_api.listen.clone());
+        }
+
+        addrs
+    }
+
+    /// Inbound and user passwords.
+    pub fn passwords(&self) -> Vec<String> {
+        let mut passwords = Vec::new();
+
+        for inbound in &self.inbounds {
+            passwords.extend(inbound.password.clone());
+            for user in inbound.users.iter().flatten() {
+                passwords.push(user.password.clone());
+            }
+        }
+
+        passwords
+    }
+}
+
+/// Address to connect to for an inbound listening on `listen`, unspecified
+/// addresses are reached through loopback.
+pub(crate) fn local_addr(listen: &str, port: u16) -> String {
+    match listen {
+        "" | "0.0.0.0" | "::" => format!("127.0.0.1:{}", port),
+        ip if ip.contains(':') => format!("[{}]:{}", ip, port),
+        ip => format!("{}:{}", ip, port),
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct LogConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disabled: Option<bool>,
     pub level: String,
+    /// Log file path, stderr when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<bool>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct DnsConfig {
     pub servers: Vec<DnsServer>,
     pub rules: Vec<DnsRule>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct DnsServer {
     pub tag: String,
     pub address: String,
     pub strategy: String,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct DnsRule {
     pub outbound: String,
     pub server: String,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct Outbound {
     pub r#type: String,
     pub tag: String,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct RouteConfig {
     pub rules: Vec<RouteRule>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct RouteRule {
     pub protocol: String,
     pub outbound: String,
@@ -1,18 +1,22 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ShadowsocksInbound {
     pub r#type: String,
     pub tag: String,
     pub listen: String,
     pub listen_port: u16,
     pub network: Option<String>,
+    /// Sets SO_REUSEADDR and SO_REUSEPORT on the listeners, so that two
+    /// sing-box instances can share the port during a blue/green restart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reuse_addr: Option<bool>,
     pub method: String,
     pub password: Option<String>,
     pub users: Option<Vec<ShadowsocksUser>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ShadowsocksUser {
     pub name: String,
     pub password: String,
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::process::Command;
+
+use crate::config::ConfigError;
+use crate::config::sing_box::SingBoxConfig;
+
+pub mod sing_box;
+pub mod xray;
+
+/// Proxy core a node runs, chosen by the panel.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoreKind {
+    #[default]
+    SingBox,
+    Xray,
+}
+
+impl CoreKind {
+    pub fn core(self) -> Arc<dyn Core> {
+        match self {
+            CoreKind::SingBox => Arc::new(sing_box::SingBox),
+            CoreKind::Xray => Arc::new(xray::Xray),
+        }
+    }
+}
+
+impl fmt::Display for CoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoreKind::SingBox => write!(f, "sing-box"),
+            CoreKind::Xray => write!(f, "xray"),
+        }
+    }
+}
+
+/// What the pod needs to know to configure, run and query a proxy core. The
+/// panel always describes a node with the sing-box shaped `SingBoxConfig`,
+/// which each core translates to its own config.
+pub trait Core: fmt::Debug + Send + Sync {
+    fn kind(&self) -> CoreKind;
+
+    /// Binary looked up in the current directory and on PATH.
+    fn binary_name(&self) -> &'static str;
+
+    /// Config file contents for the runtime config prepared by `ConfigManager`.
+    fn build_config(&self, runtime: &SingBoxConfig) -> Result<Vec<u8>, ConfigError>;
+
+    /// Adds the arguments that run the core with `config`.
+    fn run_command(&self, command: &mut Command, config: &Path, working_dir: Option<&Path>);
+
+    /// Whether SIGHUP makes the core reload its config, otherwise it is
+    /// restarted to apply a new one.
+    fn reloads_on_sighup(&self) -> bool;
+
+    /// Whether two instances can listen on the same inbound ports, as
+    /// `ProcessManager::blue_green_restart` requires.
+    fn shares_ports(&self) -> bool;
+
+    /// JSON pointer to the `StatsService` listen address in a built config.
+    fn stats_listen_pointer(&self) -> &'static str;
+
+    /// Protobuf package the core serves `StatsService` under.
+    fn stats_package(&self) -> &'static str;
+
+    /// TCP addresses the core accepts connections on once it is up.
+    fn probe_addrs(&self, config: &serde_json::Value) -> Vec<String>;
+
+    /// Whether the config creates a tun device, which needs CAP_NET_ADMIN.
+    fn uses_tun(&self, config: &serde_json::Value) -> bool;
+}
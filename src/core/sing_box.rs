@@ -0,0 +1,64 @@
+use std::path::Path;
+use tokio::process::Command;
+
+use super::{Core, CoreKind};
+use crate::config::ConfigError;
+use crate::config::sing_box::SingBoxConfig;
+
+pub const BINARY_NAME: &str = if cfg!(windows) {
+    "sing-box.exe"
+} else {
+    "sing-box"
+};
+
+#[derive(Debug)]
+pub struct SingBox;
+
+impl Core for SingBox {
+    fn kind(&self) -> CoreKind {
+        CoreKind::SingBox
+    }
+
+    fn binary_name(&self) -> &'static str {
+        BINARY_NAME
+    }
+
+    fn build_config(&self, runtime: &SingBoxConfig) -> Result<Vec<u8>, ConfigError> {
+        Ok(serde_json::to_vec(runtime)?)
+    }
+
+    fn run_command(&self, command: &mut Command, config: &Path, working_dir: Option<&Path>) {
+        command.arg("run").arg("-c").arg(config);
+        if let Some(working_dir) = working_dir {
+            command.arg("-D").arg(working_dir);
+        }
+    }
+
+    fn reloads_on_sighup(&self) -> bool {
+        true
+    }
+
+    fn shares_ports(&self) -> bool {
+        true
+    }
+
+    fn stats_listen_pointer(&self) -> &'static str {
+        "/experimental/v2ray_api/listen"
+    }
+
+    fn stats_package(&self) -> &'static str {
+        "v2ray.core.app.stats.command"
+    }
+
+    fn probe_addrs(&self, config: &serde_json::Value) -> Vec<String> {
+        serde_json::from_value::<SingBoxConfig>(config.clone())
+            .map(|config| config.probe_addrs())
+            .unwrap_or_default()
+    }
+
+    fn uses_tun(&self, config: &serde_json::Value) -> bool {
+        config["inbounds"]
+            .as_array()
+            .is_some_and(|inbounds| inbounds.iter().any(|inbound| inbound["type"] == "tun"))
+    }
+}
@@ -0,0 +1,303 @@
+use serde_json::{Value, json};
+use std::path::Path;
+use tokio::process::Command;
+use tracing::warn;
+
+use super::{Core, CoreKind};
+use crate::config::ConfigError;
+use crate::config::sing_box::shadowsocks::ShadowsocksInbound;
+use crate::config::sing_box::{SingBoxConfig, local_addr};
+
+pub const BINARY_NAME: &str = if cfg!(windows) { "xray.exe" } else { "xray" };
+
+/// Protocols Xray can sniff and route on.
+const SNIFFED_PROTOCOLS: [&str; 4] = ["http", "tls", "quic", "bittorrent"];
+
+/// Xray-core, driven through the same `StatsService` as sing-box's v2ray API.
+#[derive(Debug)]
+pub struct Xray;
+
+impl Core for Xray {
+    fn kind(&self) -> CoreKind {
+        CoreKind::Xray
+    }
+
+    fn binary_name(&self) -> &'static str {
+        BINARY_NAME
+    }
+
+    fn build_config(&self, runtime: &SingBoxConfig) -> Result<Vec<u8>, ConfigError> {
+        Ok(serde_json::to_vec(&translate(runtime)?)?)
+    }
+
+    fn run_command(&self, command: &mut Command, config: &Path, working_dir: Option<&Path>) {
+        command.arg("run").arg("-c").arg(config);
+        // Xray has no working directory flag, assets are looked up relative to it
+        if let Some(working_dir) = working_dir {
+            command.current_dir(working_dir);
+        }
+    }
+
+    fn reloads_on_sighup(&self) -> bool {
+        false
+    }
+
+    fn shares_ports(&self) -> bool {
+        false
+    }
+
+    fn stats_listen_pointer(&self) -> &'static str {
+        "/api/listen"
+    }
+
+    fn stats_package(&self) -> &'static str {
+        "xray.app.stats.command"
+    }
+
+    fn probe_addrs(&self, config: &Value) -> Vec<String> {
+        let mut addrs: Vec<String> = config["inbounds"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|inbound| {
+                inbound["settings"]["network"]
+                    .as_str()
+                    .is_none_or(|network| network.split(',').any(|n| n == "tcp"))
+            })
+            .filter_map(|inbound| {
+                let port = inbound["port"].as_u64()?.try_into().ok()?;
+                Some(local_addr(
+                    inbound["listen"].as_str().unwrap_or_default(),
+                    port,
+                ))
+            })
+            .collect();
+
+        if let Some(listen) = config.pointer(self.stats_listen_pointer()) {
+            addrs.extend(listen.as_str().map(str::to_string));
+        }
+
+        addrs
+    }
+
+    fn uses_tun(&self, config: &Value) -> bool {
+        config["inbounds"]
+            .as_array()
+            .is_some_and(|inbounds| inbounds.iter().any(|inbound| inbound["protocol"] == "tun"))
+    }
+}
+
+/// Translates the sing-box shaped runtime config to an Xray config.
+fn translate(runtime: &SingBoxConfig) -> Result<Value, ConfigError> {
+    let rules: Vec<Value> = runtime
+        .route
+        .rules
+        .iter()
+        .filter_map(|rule| {
+            if !SNIFFED_PROTOCOLS.contains(&rule.protocol.as_str()) {
+                warn!(
+                    "Xray can't route on protocol {}, skipping the rule",
+                    rule.protocol
+                );
+                return None;
+            }
+            Some(json!({
+                "type": "field",
+                "protocol": [rule.protocol],
+                "outboundTag": rule.outbound,
+            }))
+        })
+        .collect();
+
+    let inbounds = runtime
+        .inbounds
+        .iter()
+        .enumerate()
+        .map(|(i, inbound)| {
+            let mut inbound = translate_inbound(i, inbound)?;
+            // Routing on protocols needs the inbound to sniff them
+            if !rules.is_empty() {
+                inbound["sniffing"] = json!({
+                    "enabled": true,
+                    "destOverride": ["http", "tls", "quic"],
+                });
+            }
+            Ok(inbound)
+        })
+        .collect::<Result<Vec<Value>, ConfigError>>()?;
+
+    let outbounds = runtime
+        .outbounds
+        .iter()
+        .enumerate()
+        .map(|(i, outbound)| {
+            let protocol = match outbound.r#type.as_str() {
+                "direct" => "freedom",
+                "block" => "blackhole",
+                "dns" => "dns",
+                other => {
+                    return Err(ConfigError::invalid(
+                        format!("runtime.outbounds[{}].type", i),
+                        format!("Xray has no {} outbound", other),
+                    ));
+                }
+            };
+            Ok(json!({ "tag": outbound.tag, "protocol": protocol }))
+        })
+        .collect::<Result<Vec<Value>, ConfigError>>()?;
+
+    let loglevel = match runtime.log.level.as_str() {
+        _ if runtime.log.disabled == Some(true) => "none",
+        "trace" | "debug" => "debug",
+        "info" => "info",
+        "warn" => "warning",
+        _ => "error",
+    };
+    let mut log = json!({ "loglevel": loglevel, "access": "none" });
+    if let Some(output) = &runtime.log.output {
+        log["error"] = output.clone().into();
+    }
+
+    let mut config = json!({
+        "log": log,
+        "inbounds": inbounds,
+        "outbounds": outbounds,
+        "routing": { "rules": rules },
+    });
+
+    if !runtime.dns.servers.is_empty() {
+        let servers: Vec<&str> = runtime
+            .dns
+            .servers
+            .iter()
+            .map(|server| match server.address.as_str() {
+                "local" => "localhost",
+                address => address,
+            })
+            .collect();
+        config["dns"] = json!({ "servers": servers });
+    }
+
+    if let Some(experimental) = &runtime.experimental {
+        config["api"] = json!({
+            "tag": "api",
+            "listen": experimental.v2ray_api.listen,
+            "services": ["StatsService"],
+        });
+        config["stats"] = json!({});
+        config["policy"] = json!({
+            "levels": {
+                "0": { "statsUserUplink": true, "statsUserDownlink": true },
+            },
+            "system": {
+                "statsInboundUplink": true,
+                "statsInboundDownlink": true,
+                "statsOutboundUplink": true,
+                "statsOutboundDownlink": true,
+            },
+        });
+    }
+
+    Ok(config)
+}
+
+fn translate_inbound(index: usize, inbound: &ShadowsocksInbound) -> Result<Value, ConfigError> {
+    if inbound.r#type != "shadowsocks" {
+        return Err(ConfigError::invalid(
+            format!("runtime.inbounds[{}].type", index),
+            format!("Xray has no {} inbound", inbound.r#type),
+        ));
+    }
+
+    // Shadowsocks 2022 users share the server's method, older methods are per user
+    let is_2022 = inbound.method.starts_with("2022-");
+    let clients: Vec<Value> = inbound
+        .users
+        .iter()
+        .flatten()
+        .map(|user| {
+            let mut client = json!({ "email": user.name, "password": user.password });
+            if !is_2022 {
+                client["method"] = inbound.method.clone().into();
+            }
+            client
+        })
+        .collect();
+
+    let mut settings = json!({
+        "method": inbound.method,
+        "network": inbound.network.as_deref().unwrap_or("tcp,udp"),
+    });
+    if let Some(password) = &inbound.password {
+        settings["password"] = password.clone().into();
+    }
+    if !clients.is_empty() {
+        settings["clients"] = clients.into();
+    }
+
+    let listen = match inbound.listen.as_str() {
+        "" => "0.0.0.0",
+        listen => listen,
+    };
+
+    Ok(json!({
+        "tag": inbound.tag,
+        "listen": listen,
+        "port": inbound.listen_port,
+        "protocol": "shadowsocks",
+        "settings": settings,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let runtime: SingBoxConfig = serde_json::from_value(json!({
+            "log": { "level": "warn", "output": "/run/pod/sing-box.log.pipe" },
+            "dns": { "servers": [], "rules": [] },
+            "outbounds": [{ "type": "direct", "tag": "direct" }],
+            "route": { "rules": [] },
+            "inbounds": [{
+                "type": "shadowsocks",
+                "tag": "ss-in",
+                "listen": "::",
+                "listen_port": 8388,
+                "network": "tcp",
+                "method": "2022-blake3-aes-128-gcm",
+                "password": "server",
+                "users": [{ "name": "alice", "password": "a" }],
+            }],
+            "experimental": {
+                "v2ray_api": {
+                    "listen": "localhost:10085",
+                    "stats": { "enabled": true, "inbounds": [], "outbounds": [], "users": [] },
+                },
+            },
+        }))
+        .unwrap();
+
+        let config = translate(&runtime).unwrap();
+
+        assert_eq!(config["log"]["loglevel"], "warning");
+        assert_eq!(config["log"]["error"], "/run/pod/sing-box.log.pipe");
+        assert_eq!(
+            config["inbounds"][0]["settings"],
+            json!({
+                "method": "2022-blake3-aes-128-gcm",
+                "network": "tcp",
+                "password": "server",
+                "clients": [{ "email": "alice", "password": "a" }],
+            })
+        );
+        assert_eq!(config["inbounds"][0]["listen"], "::");
+        assert_eq!(config["outbounds"][0]["protocol"], "freedom");
+        assert_eq!(config["api"]["listen"], "localhost:10085");
+        assert_eq!(
+            Xray.probe_addrs(&config),
+            vec!["127.0.0.1:8388", "localhost:10085"]
+        );
+    }
+}
@@ -0,0 +1,21 @@
+//! Runs sing-box or Xray-core for a panel node: fetches its config, keeps
+//! the core running and reports traffic back. `pod::run` is the whole pod as
+//! the `next-proxies-pod` binary runs it, the other modules can be used on
+//! their own to embed parts of it.
+
+pub mod api;
+pub mod config;
+pub mod core;
+pub mod node;
+pub mod pod;
+pub mod process;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use api::server::{PanelError, ServerFetch, StatsReport};
+pub use api::v2ray_api::{StatsError, StatsFormatResponse, V2rayApi};
+pub use config::sing_box::SingBoxConfig;
+pub use config::{ConfigError, ConfigManager, ConfigOptions, ConfigResponse};
+pub use core::{Core, CoreKind};
+pub use process::error::ProcessError;
+pub use process::{ProcessManager, ProcessOptions};
@@ -0,0 +1,243 @@
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::info;
+
+use crate::api::server::{PanelError, auth_header};
+
+/// Failure to load the `--nodes-file`.
+#[derive(Debug, Error)]
+pub enum NodesFileError {
+    #[error("failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid nodes file {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("nodes file {} lists no nodes", path.display())]
+    Empty { path: PathBuf },
+
+    #[error("invalid node name `{0}`, use letters, digits, - and _")]
+    InvalidName(String),
+
+    #[error("duplicate node name `{0}`")]
+    DuplicateName(String),
+
+    #[error("invalid token for node `{0}`: {1}")]
+    InvalidToken(String, #[source] PanelError),
+}
+
+/// A panel node served by the pod, as listed in `--nodes-file`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeSpec {
+    /// Unique name, used for the node's runtime dir, log file and cgroup.
+    pub name: String,
+
+    pub url: String,
+
+    pub auth: String,
+
+    /// URL warnings and errors are posted to, defaults to <url>/logs.
+    pub logs_url: Option<String>,
+}
+
+impl NodeSpec {
+    /// Reads a JSON array of nodes.
+    pub fn load(path: &Path) -> Result<Vec<Self>, NodesFileError> {
+        let content = fs::read_to_string(path).map_err(|source| NodesFileError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let nodes: Vec<Self> =
+            serde_json::from_str(&content).map_err(|source| NodesFileError::Parse {
+                path: path.to_path_buf(),
+                source,
+            })?;
+
+        if nodes.is_empty() {
+            return Err(NodesFileError::Empty {
+                path: path.to_path_buf(),
+            });
+        }
+
+        Self::validate(&nodes)?;
+        Ok(nodes)
+    }
+
+    /// Checks that the names are unique and usable in paths, and that every
+    /// token can be sent to the panel.
+    pub fn validate(nodes: &[Self]) -> Result<(), NodesFileError> {
+        let mut names = HashSet::new();
+        for node in nodes {
+            let valid = !node.name.is_empty()
+                && node
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(NodesFileError::InvalidName(node.name.clone()));
+            }
+            if !names.insert(node.name.as_str()) {
+                return Err(NodesFileError::DuplicateName(node.name.clone()));
+            }
+            auth_header(&node.auth)
+                .map_err(|e| NodesFileError::InvalidToken(node.name.clone(), e))?;
+        }
+
+        Ok(())
+    }
+
+    /// `path` with the node name appended to its stem, `sing-box.log` becomes
+    /// `sing-box-<name>.log`, for files and cgroups the nodes can't share.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(extension) => format!("{}-{}.{}", stem, self.name, extension.to_string_lossy()),
+            None => format!("{}-{}", stem, self.name),
+        };
+        path.with_file_name(name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeState {
+    Starting,
+    Running { pid: u32 },
+    Restarting { attempt: u32 },
+    Exited,
+    Stopped,
+    Failed { error: String },
+    AuthFailed { status: StatusCode },
+}
+
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeState::Starting => write!(f, "starting"),
+            NodeState::Running { pid } => write!(f, "running (pid={})", pid),
+            NodeState::Restarting { attempt } => write!(f, "restarting (attempt {})", attempt),
+            NodeState::Exited => write!(f, "exited"),
+            NodeState::Stopped => write!(f, "stopped"),
+            NodeState::Failed { error } => write!(f, "failed: {}", error),
+            NodeState::AuthFailed { status } => write!(f, "auth failed ({})", status),
+        }
+    }
+}
+
+/// Last known state of every node, shared by their runners.
+#[derive(Clone, Debug, Default)]
+pub struct NodeStatuses(Arc<parking_lot::Mutex<BTreeMap<String, NodeState>>>);
+
+impl NodeStatuses {
+    pub fn set(&self, name: &str, state: NodeState) {
+        let previous = self.0.lock().insert(name.to_string(), state.clone());
+        if previous.as_ref() != Some(&state) {
+            info!("Node {} is {}", name, state);
+        }
+    }
+
+    /// Handle to the state of the node `name`.
+    pub fn node(&self, name: &str) -> NodeStatus {
+        NodeStatus {
+            name: name.to_string(),
+            statuses: self.clone(),
+        }
+    }
+
+    /// One `<name>: <state>` entry per node, ordered by name.
+    pub fn summary(&self) -> String {
+        self.0
+            .lock()
+            .iter()
+            .map(|(name, state)| format!("{}: {}", name, state))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// One node's entry in `NodeStatuses`.
+#[derive(Clone, Debug)]
+pub struct NodeStatus {
+    name: String,
+    statuses: NodeStatuses,
+}
+
+impl NodeStatus {
+    pub fn set(&self, state: NodeState) {
+        self.statuses.set(&self.name, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_load_nodes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.child("nodes.json");
+
+        fs::write(
+            &path,
+            r#"[
+                { "name": "hk-1", "url": "https://panel/api?id=1", "auth": "a" },
+                { "name": "hk-2", "url": "https://panel/api?id=2", "auth": "b", "logsUrl": "https://logs" }
+            ]"#,
+        )
+        .unwrap();
+        let nodes = NodeSpec::load(&path).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[1].logs_url.as_deref(), Some("https://logs"));
+        assert_eq!(
+            nodes[0].path(Path::new("/var/log/sing-box.log")),
+            Path::new("/var/log/sing-box-hk-1.log")
+        );
+
+        fs::write(
+            &path,
+            r#"[
+                { "name": "hk-1", "url": "https://panel/api?id=1", "auth": "a" },
+                { "name": "hk-1", "url": "https://panel/api?id=2", "auth": "b" }
+            ]"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            NodeSpec::load(&path),
+            Err(NodesFileError::DuplicateName(name)) if name == "hk-1"
+        ));
+
+        fs::write(&path, r#"[{ "name": "../etc", "url": "u", "auth": "a" }]"#).unwrap();
+        assert!(matches!(
+            NodeSpec::load(&path),
+            Err(NodesFileError::InvalidName(_))
+        ));
+
+        fs::write(&path, r#"[{ "name": "hk-1", "url": "u", "auth": "a\nb" }]"#).unwrap();
+        assert!(matches!(
+            NodeSpec::load(&path),
+            Err(NodesFileError::InvalidToken(name, _)) if name == "hk-1"
+        ));
+
+        fs::write(&path, "[]").unwrap();
+        assert!(matches!(
+            NodeSpec::load(&path),
+            Err(NodesFileError::Empty { .. })
+        ));
+    }
+}
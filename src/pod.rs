@@ -0,0 +1,1155 @@
+use clap::Parser;
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::{self, JoinSet};
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
+
+use crate::api;
+use crate::api::server::{PanelError, StatsReport};
+use crate::api::v2ray_api::{StatsError, StatsFormatResponse, V2rayApi};
+use crate::config;
+use crate::config::FetchStatus;
+use crate::config::diff::ChangeKind;
+use crate::core::CoreKind;
+use crate::node::{NodeSpec, NodeState, NodeStatus, NodeStatuses, NodesFileError};
+use crate::process::{
+    ProcessManager, ProcessOptions,
+    error::ProcessError,
+    event::ProcessEvent,
+    install::{InstallOptions, Installer},
+    limits::{CgroupOptions, ResourceLimits},
+    log::ErrorLogOptions,
+    log_file::LogFileOptions,
+    pid_file::PidFile,
+    privileges::Credentials,
+    reload::{Reload, ReloadScheduler},
+    version::SingBoxVersion,
+};
+
+/// Failure to start the pod or one of its nodes.
+#[derive(Debug, Error)]
+pub enum PodError {
+    #[error("failed to look up the sing-box user: {0}")]
+    Credentials(#[source] io::Error),
+
+    #[error(transparent)]
+    NodesFile(#[from] NodesFileError),
+
+    #[error("failed to create the core install dir: {0}")]
+    CoreDir(#[source] io::Error),
+
+    #[error(transparent)]
+    Config(#[from] config::ConfigError),
+
+    #[error(transparent)]
+    Process(#[from] ProcessError),
+
+    #[error("failed to connect to the stats API: {0}")]
+    StatsApi(#[from] StatsError),
+
+    #[error("{failed} of {total} nodes failed")]
+    NodesFailed { failed: usize, total: usize },
+}
+
+#[derive(Parser)]
+#[command(name = "next-proxies-pod")]
+pub struct Args {
+    #[arg(long, required_unless_present = "nodes_file")]
+    pub url: Option<String>,
+
+    #[arg(long, required_unless_present = "nodes_file")]
+    pub auth: Option<String>,
+
+    /// JSON file listing several panel nodes to serve, each with its own
+    /// sing-box: [{"name": "hk-1", "url": "...", "auth": "...", "logsUrl": "..."}].
+    /// Runtime and core dirs get a sub-directory per node, the log file and
+    /// cgroup a `-<name>` suffix
+    #[arg(long, conflicts_with_all = ["url", "auth", "logs_url"])]
+    pub nodes_file: Option<PathBuf>,
+
+    #[arg(long, default_value = "info")]
+    pub log_level: String,
+
+    /// Directory for the generated sing-box config and pid file, defaults to
+    /// /run/next-proxies-pod as root and $XDG_RUNTIME_DIR/next-proxies-pod
+    /// otherwise, a temp dir without either. Created with mode 0700, or 0750
+    /// for the group of --singbox-user, an existing directory must already be
+    /// private to the pod
+    #[arg(long)]
+    pub runtime_dir: Option<PathBuf>,
+
+    /// Path to the sing-box binary, defaults to ./sing-box or sing-box on PATH.
+    /// A version installed in --core-dir takes precedence
+    #[arg(long)]
+    pub singbox_bin: Option<PathBuf>,
+
+    /// Path to the Xray binary, defaults to ./xray or xray on PATH. Used when
+    /// the panel runs the node on Xray-core
+    #[arg(long)]
+    pub xray_bin: Option<PathBuf>,
+
+    /// Extra argument passed to sing-box, can be repeated
+    #[arg(long, allow_hyphen_values = true)]
+    pub singbox_arg: Vec<String>,
+
+    /// Working directory passed to sing-box with -D
+    #[arg(long)]
+    pub singbox_dir: Option<PathBuf>,
+
+    /// Environment variable for sing-box as KEY=VALUE, can be repeated
+    #[arg(long, value_parser = parse_env)]
+    pub singbox_env: Vec<(String, String)>,
+
+    /// Seconds to batch user additions into a single reload. Without
+    /// --drain-period every reload drops all connections
+    #[arg(long, default_value_t = 0)]
+    pub user_reload_delay: u64,
+
+    /// Pins the sing-box log level, overriding the panel
+    #[arg(long)]
+    pub singbox_log_level: Option<String>,
+
+    /// Writes sing-box output to this rotating file instead of the pod log
+    #[arg(long)]
+    pub singbox_log_file: Option<PathBuf>,
+
+    /// Size in MiB after which the sing-box log file is rotated
+    #[arg(long, default_value = "10", value_parser = parse_mib)]
+    pub singbox_log_max_size: u64,
+
+    /// Number of rotated sing-box log files to keep
+    #[arg(long, default_value_t = 5)]
+    pub singbox_log_max_files: usize,
+
+    /// Seconds to wait for sing-box to exit before killing it
+    #[arg(long, default_value_t = 10)]
+    pub stop_timeout: u64,
+
+    /// Restart sing-box when it exits unexpectedly
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub auto_restart: bool,
+
+    /// Maximum seconds between attempts to restart a crashed sing-box, or to
+    /// start a node again after it failed to
+    #[arg(long, default_value_t = 60)]
+    pub restart_max_delay: u64,
+
+    /// Seconds the previous sing-box keeps serving its connections when an
+    /// upgrade, structural config change or user addition starts a new one
+    /// alongside it. Unset reloads sing-box in place, dropping every connection.
+    /// Needs sing-box 1.12.0 or later
+    #[arg(long)]
+    pub drain_period: Option<u64>,
+
+    /// Seconds to wait for sing-box to listen on its ports after starting
+    #[arg(long, default_value_t = 30)]
+    pub ready_timeout: u64,
+
+    /// Leave sing-box running when the pod exits and adopt it on the next start,
+    /// so pod restarts keep user connections. Under systemd this needs
+    /// KillMode=process. Needs a runtime dir that outlives the pod
+    #[arg(long)]
+    pub adopt: bool,
+
+    /// User to run sing-box as, by name or uid. Requires the pod to run as root,
+    /// sing-box keeps only the capabilities needed to bind low ports
+    #[arg(long)]
+    pub singbox_user: Option<String>,
+
+    /// Group to run sing-box as, defaults to the user's primary group
+    #[arg(long, requires = "singbox_user")]
+    pub singbox_group: Option<String>,
+
+    /// Maximum number of open files for sing-box (RLIMIT_NOFILE)
+    #[arg(long)]
+    pub rlimit_nofile: Option<u64>,
+
+    /// Maximum address space of sing-box in MiB (RLIMIT_AS)
+    #[arg(long, value_parser = parse_mib)]
+    pub rlimit_as: Option<u64>,
+
+    /// Maximum core dump size of sing-box in MiB, 0 disables core dumps (RLIMIT_CORE)
+    #[arg(long, value_parser = parse_mib)]
+    pub rlimit_core: Option<u64>,
+
+    /// Niceness sing-box runs with, from -20 to 19, regardless of the pod's
+    #[arg(long, allow_hyphen_values = true)]
+    pub nice: Option<i32>,
+
+    /// cgroup v2 directory to run sing-box in, its parent must be delegated to the pod
+    #[arg(long)]
+    pub cgroup: Option<PathBuf>,
+
+    /// memory.max of the sing-box cgroup in MiB
+    #[arg(long, requires = "cgroup", value_parser = parse_mib)]
+    pub cgroup_memory_max: Option<u64>,
+
+    /// cpu.max of the sing-box cgroup, "<quota> <period>" in microseconds
+    #[arg(long, requires = "cgroup")]
+    pub cgroup_cpu_max: Option<String>,
+
+    /// URL sing-box warnings and errors are posted to, defaults to <url>/logs
+    #[arg(long)]
+    pub logs_url: Option<String>,
+
+    /// Distinct sing-box log entries posted per reporting cycle, 0 disables posting
+    #[arg(long, default_value_t = 50)]
+    pub logs_max_entries: usize,
+
+    /// Replace user passwords in posted sing-box logs
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub logs_redact_passwords: bool,
+
+    /// Replace IP addresses in posted sing-box logs
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub logs_redact_ips: bool,
+
+    /// Directory for sing-box releases installed on request of the panel,
+    /// managed installation is disabled when unset
+    #[arg(long)]
+    pub core_dir: Option<PathBuf>,
+
+    /// minisign public key sing-box release archives must be signed with
+    #[arg(long)]
+    pub core_public_key: Option<String>,
+
+    /// Base URL or directory to fetch sing-box release archives from
+    #[arg(long)]
+    pub core_mirror: Option<String>,
+}
+
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", s))
+}
+
+/// Runtime dir of a pod running as root when --runtime-dir is unset.
+const DEFAULT_RUNTIME_DIR: &str = "/run/next-proxies-pod";
+
+/// Runtime dir when --runtime-dir is unset. It must be the same for the next
+/// pod instance to find a sing-box left running by this one, `None` falls
+/// back to a temp dir.
+fn default_runtime_dir() -> Option<PathBuf> {
+    #[cfg(unix)]
+    if nix::unistd::geteuid().is_root() {
+        return Some(PathBuf::from(DEFAULT_RUNTIME_DIR));
+    }
+
+    std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("next-proxies-pod"))
+}
+
+/// Parses a size in MiB into bytes.
+fn parse_mib(s: &str) -> Result<u64, String> {
+    let mib = s.parse::<u64>().map_err(|e| e.to_string())?;
+    mib.checked_mul(1024 * 1024)
+        .ok_or_else(|| format!("{} MiB is too large", mib))
+}
+
+#[derive(Debug)]
+enum ReportingTask {
+    FetchConfig,
+
+    PostStats,
+
+    PostLogs,
+
+    ConnectStatsApi,
+
+    ReloadConfig,
+
+    UpgradeCore,
+}
+
+impl ReportingTask {
+    /// Handle the task
+    async fn handle(
+        self,
+        config: &mut config::ConfigManager,
+        fetch: &mut api::server::ServerFetch,
+        v2ray_api: &mut Option<V2rayApi>,
+        manager: &ProcessManager,
+        scheduler: &mut ReloadScheduler,
+        installer: &Option<Installer>,
+    ) {
+        match self {
+            ReportingTask::FetchConfig => {
+                if let Err(e) = config.fetch().await {
+                    match e {
+                        config::ConfigError::Panel(PanelError::AuthBackoff { .. }) => {
+                            debug!("Skipped fetching config: {}", e)
+                        }
+                        _ => error!("Error fetching config: {}", e),
+                    }
+                } else {
+                    manager.set_log_secrets(config.secrets());
+                    info!("Fetch config done");
+                }
+            }
+            ReportingTask::PostStats => {
+                // without the v2ray api there are no stats, but the rest of the report still matters
+                let mut stats = match v2ray_api {
+                    Some(v2ray_api) => match v2ray_api.query_all_stats(true).await {
+                        Ok(stats) => stats,
+                        Err(e) => {
+                            error!("Error during gRPC query: {}", e);
+                            return;
+                        }
+                    },
+                    None => StatsFormatResponse::default(),
+                };
+                if let Some(unreported) = manager.take_unreported_stats() {
+                    stats.merge(unreported);
+                }
+
+                debug!("Stats query result: {:?}", stats);
+                let config_error = match &config.fetch_status {
+                    Some(FetchStatus::Error(e)) => Some(e.clone()),
+                    _ => None,
+                };
+                let report = StatsReport {
+                    stats,
+                    config_diffs: config.pending_diffs.clone(),
+                    core_version: config.core_version().cloned(),
+                    config_error,
+                };
+                if let Err(e) = fetch.post_stats(&report).await {
+                    // sing-box's counters were reset by the query, keep them for the next report
+                    manager.restore_stats(report.stats);
+                    match e {
+                        PanelError::AuthBackoff { .. } => debug!("Skipped posting stats: {}", e),
+                        _ => error!("Error posting stats: {}", e),
+                    }
+                } else {
+                    config.pending_diffs.clear();
+                    info!("Stats posted successfully!");
+                }
+            }
+            ReportingTask::PostLogs => {
+                let report = manager.take_error_logs();
+                if report.is_empty() {
+                    return;
+                }
+
+                if let Err(e) = fetch.post_logs(&report).await {
+                    manager.restore_error_logs(report);
+                    match e {
+                        PanelError::AuthBackoff { .. } => {
+                            debug!("Skipped posting sing-box logs: {}", e)
+                        }
+                        _ => error!("Error posting sing-box logs: {}", e),
+                    }
+                } else {
+                    info!("Posted {} sing-box log entries", report.entries.len());
+                }
+            }
+            ReportingTask::ConnectStatsApi => {
+                if !config.stats_enabled() {
+                    return;
+                }
+
+                let endpoint = manager
+                    .stats_endpoint()
+                    .unwrap_or_else(|| config.v2ray_api_endpoint.clone());
+                match V2rayApi::new(
+                    format!("http://{}", endpoint),
+                    config.core().stats_package(),
+                )
+                .await
+                {
+                    Ok(api) => {
+                        *v2ray_api = Some(api);
+                        info!("Connected to V2Ray API");
+                    }
+                    Err(e) => error!("Error connecting to V2Ray API: {}", e),
+                }
+            }
+            ReportingTask::ReloadConfig => {
+                let change = match config.fetch_status {
+                    Some(FetchStatus::Updated(change)) => Some(change),
+                    _ => None,
+                };
+                let Some(reload) = scheduler.update(change, config.last_diff.as_ref()) else {
+                    return;
+                };
+
+                // Revoked users must lose their connections, also those on a
+                // draining sing-box, the rest can drain
+                if reload == Reload::Revocation {
+                    manager.stop_draining().await;
+                }
+                if reload != Reload::Revocation && manager.blue_green_enabled() {
+                    // Runs for the drain period, keep reporting meanwhile
+                    manager.spawn_blue_green_restart();
+                } else if let Err(e) = manager.reload().await {
+                    error!("Error reloading sing-box: {}", e);
+                } else {
+                    info!("Reloaded sing-box successfully");
+                }
+            }
+            ReportingTask::UpgradeCore => {
+                let Some(installer) = installer else {
+                    return;
+                };
+                if let Some(version) = installer.take_upgraded() {
+                    config.set_core_version(version);
+                }
+                // Managed installs are sing-box releases
+                if config.core().kind() != CoreKind::SingBox {
+                    return;
+                }
+                let Some(release) = config.config.as_ref().and_then(|c| c.core.clone()) else {
+                    return;
+                };
+                if !installer.wants(&release, config.core_version()) {
+                    return;
+                }
+
+                info!("Upgrading sing-box to {}", release.version);
+                // A blue/green restart runs for the drain period, keep reporting
+                // meanwhile, the version is picked up on the next cycle
+                let installer = installer.clone();
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    match installer.upgrade(&manager, &release).await {
+                        Ok(version) => info!("Upgraded sing-box to {}", version.version),
+                        Err(e) => error!("Error upgrading sing-box to {}: {}", release.version, e),
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Producer that generates tasks and sends them to the queue
+async fn reporting_tasks_producer(tx: mpsc::Sender<ReportingTask>, interval_secs: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+
+    // skip the first tick
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = tx.send(ReportingTask::FetchConfig).await {
+            error!("Error sending FetchConfig task: {}", e);
+            break;
+        }
+
+        if let Err(e) = tx.send(ReportingTask::PostStats).await {
+            error!("Error sending PostStats task: {}", e);
+            break;
+        }
+
+        if let Err(e) = tx.send(ReportingTask::PostLogs).await {
+            error!("Error sending PostLogs task: {}", e);
+            break;
+        }
+
+        if let Err(e) = tx.send(ReportingTask::ReloadConfig).await {
+            error!("Error sending ReloadConfig task: {}", e);
+            break;
+        }
+
+        // last, so stats are posted before an upgrade restarts sing-box
+        if let Err(e) = tx.send(ReportingTask::UpgradeCore).await {
+            error!("Error sending UpgradeCore task: {}", e);
+            break;
+        }
+    }
+}
+
+/// Turns sing-box lifecycle events into tasks
+async fn process_events_forwarder(
+    mut events: broadcast::Receiver<ProcessEvent>,
+    tx: mpsc::Sender<ReportingTask>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(ProcessEvent::Started { pid }) => {
+                info!("sing-box (pid={}) started, reconnecting V2Ray API", pid);
+                if let Err(e) = tx.send(ReportingTask::ConnectStatsApi).await {
+                    error!("Error sending ConnectStatsApi task: {}", e);
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Missed {} sing-box process events", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Consumer that receives tasks from the queue and executes them
+async fn reporting_tasks_consumer(
+    mut rx: mpsc::Receiver<ReportingTask>,
+    mut config: config::ConfigManager,
+    mut fetch: api::server::ServerFetch,
+    mut v2ray_api: Option<V2rayApi>,
+    manager: Arc<ProcessManager>,
+    mut scheduler: ReloadScheduler,
+    installer: Option<Installer>,
+) {
+    while let Some(task) = rx.recv().await {
+        task.handle(
+            &mut config,
+            &mut fetch,
+            &mut v2ray_api,
+            &manager,
+            &mut scheduler,
+            &installer,
+        )
+        .await;
+    }
+}
+
+/// Reflects the panel rejecting the token, and accepting it again, in the
+/// node's state once per reporting cycle
+async fn track_auth_state(
+    fetch: api::server::ServerFetch,
+    manager: Arc<ProcessManager>,
+    status: NodeStatus,
+    interval_secs: u64,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    let mut auth_failed = false;
+
+    loop {
+        interval.tick().await;
+        match fetch.auth_failure() {
+            Some(failure) => {
+                auth_failed = true;
+                status.set(NodeState::AuthFailed {
+                    status: failure.status,
+                });
+            }
+            None if auth_failed => {
+                auth_failed = false;
+                if let Some(pid) = manager.pid().await {
+                    status.set(NodeState::Running { pid });
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// Wrap producer and consumer and run concurrently
+async fn spawn_reporting_tasks(
+    config: config::ConfigManager,
+    fetch: api::server::ServerFetch,
+    v2ray_api: Option<V2rayApi>,
+    manager: Arc<ProcessManager>,
+    scheduler: ReloadScheduler,
+    installer: Option<Installer>,
+    status: NodeStatus,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let interval_secs = config.config.as_ref().unwrap().guard_config.reporting_cycle;
+    info!("Reporting interval: {}s", interval_secs);
+
+    // Create a mpsc channel
+    let (tx, rx) = mpsc::channel::<ReportingTask>(100);
+
+    // Dropping the set aborts the tasks, so they end with the node
+    let mut tasks = JoinSet::new();
+
+    // Clones of the panel client share its auth state
+    let auth_state = track_auth_state(fetch.clone(), Arc::clone(&manager), status, interval_secs);
+    tasks.spawn(
+        async move {
+            auth_state.await;
+            "Auth state tracker"
+        }
+        .in_current_span(),
+    );
+
+    // Start the consumer (task handler)
+    let consumer = reporting_tasks_consumer(
+        rx,
+        config,
+        fetch,
+        v2ray_api,
+        Arc::clone(&manager),
+        scheduler,
+        installer,
+    );
+    tasks.spawn(
+        async move {
+            consumer.await;
+            "Consumer"
+        }
+        .in_current_span(),
+    );
+
+    // Reconnect the stats client whenever sing-box comes back
+    let events = process_events_forwarder(manager.subscribe(), tx.clone());
+    tasks.spawn(
+        async move {
+            events.await;
+            "Process events forwarder"
+        }
+        .in_current_span(),
+    );
+
+    // Start the producer (task generator)
+    let producer = reporting_tasks_producer(tx, interval_secs);
+    tasks.spawn(
+        async move {
+            producer.await;
+            "Producer"
+        }
+        .in_current_span(),
+    );
+
+    // Wait for either the producer or consumer to finish
+    if let Some(Ok(task)) = tasks.join_next().await {
+        error!("{} ended unexpectedly.", task);
+    }
+
+    Ok(())
+}
+
+async fn setup_process_manager(
+    config: &config::ConfigManager,
+    options: ProcessOptions,
+) -> Result<ProcessManager, ProcessError> {
+    let manager = ProcessManager::new(config.runtime_path.clone(), options);
+    manager.set_log_secrets(config.secrets());
+    if let Err(e) = manager.start().await {
+        error!("Error starting sing-box: {}", e);
+        return Err(e);
+    }
+    // An adopted sing-box still runs the config of the previous pod instance,
+    // apply changes the way the reporting loop would to keep its connections
+    if manager.is_adopted() {
+        match config.fetch_status {
+            Some(FetchStatus::Updated(ChangeKind::Structural)) if manager.blue_green_enabled() => {
+                manager.spawn_blue_green_restart();
+            }
+            Some(FetchStatus::Updated(ChangeKind::UserOnly | ChangeKind::Structural)) => {
+                manager.reload().await?;
+            }
+            _ => {}
+        }
+    }
+    info!("sing-box started successfully");
+    Ok(manager)
+}
+
+async fn shutdown_manager(manager: &ProcessManager, detach: bool) {
+    // Nothing would find a draining sing-box again, it must not outlive the pod
+    manager.stop_draining().await;
+
+    if !manager.is_running().await {
+        return;
+    }
+
+    if detach {
+        info!("Leaving sing-box running for the next pod instance to adopt");
+        return;
+    }
+
+    match manager.stop().await {
+        Ok(Some(status)) => info!("sing-box stopped with status: {}", status),
+        Ok(None) => {}
+        Err(e) => error!("Error stopping sing-box: {}", e),
+    }
+}
+
+/// Mirrors sing-box lifecycle events into the node's status
+async fn track_node_state(
+    name: String,
+    mut events: broadcast::Receiver<ProcessEvent>,
+    statuses: NodeStatuses,
+) {
+    loop {
+        let state = match events.recv().await {
+            Ok(ProcessEvent::Started { pid }) => NodeState::Running { pid },
+            Ok(ProcessEvent::Restarting { attempt }) => NodeState::Restarting { attempt },
+            Ok(ProcessEvent::Exited { .. }) => NodeState::Exited,
+            Ok(ProcessEvent::Stopped) => NodeState::Stopped,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        statuses.set(&name, state);
+    }
+}
+
+/// Logs the state of every node once a minute
+async fn log_node_statuses(statuses: NodeStatuses) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+    // skip the first tick
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        info!("Nodes: {}", statuses.summary());
+    }
+}
+
+/// First delay before starting a node again after it failed to, doubled on
+/// every failure up to `--restart-max-delay`
+const NODE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// State of a node whose runner failed, telling a rejected token apart.
+fn failed_state(error: &PodError) -> NodeState {
+    match error {
+        PodError::Config(config::ConfigError::Panel(
+            e @ (PanelError::Status { status, .. } | PanelError::AuthBackoff { status, .. }),
+        )) if e.is_auth_failure() => NodeState::AuthFailed { status: *status },
+        _ => NodeState::Failed {
+            error: error.to_string(),
+        },
+    }
+}
+
+/// Runs one panel node until `shutdown` fires or its reporting ends, starting
+/// it again with a growing delay while it fails to start
+async fn run_node(
+    node: NodeSpec,
+    args: Arc<Args>,
+    multi: bool,
+    credentials: Option<Credentials>,
+    statuses: NodeStatuses,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let max_delay = Duration::from_secs(args.restart_max_delay);
+    let mut attempt = 0;
+
+    // Shared by every attempt, so a rejected token is retried on its backoff
+    let mut fetch = match api::server::ServerFetch::new(node.url.clone(), node.auth.clone()) {
+        Ok(fetch) => fetch,
+        Err(e) => {
+            error!("Node {} failed: {}", node.name, e);
+            statuses.set(
+                &node.name,
+                NodeState::Failed {
+                    error: e.to_string(),
+                },
+            );
+            return;
+        }
+    };
+    fetch.logs_url = node.logs_url.clone();
+
+    loop {
+        let result = serve_node(
+            node.clone(),
+            fetch.clone(),
+            Arc::clone(&args),
+            multi,
+            credentials,
+            statuses.clone(),
+            shutdown.clone(),
+        )
+        .await;
+        let Err(e) = result else {
+            return;
+        };
+
+        attempt += 1;
+        let delay = match fetch.auth_failure() {
+            Some(failure) => failure
+                .retry_at
+                .saturating_duration_since(std::time::Instant::now()),
+            None => NODE_RETRY_DELAY
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(max_delay),
+        };
+        error!(
+            "Node {} failed to start: {}, retrying in {}s (attempt {})",
+            node.name,
+            e,
+            delay.as_secs(),
+            attempt
+        );
+        statuses.set(&node.name, failed_state(&e));
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+/// Sets up and runs the config manager, sing-box and reporting loop of one
+/// panel node until `shutdown` fires or its reporting ends
+async fn serve_node(
+    node: NodeSpec,
+    fetch: api::server::ServerFetch,
+    args: Arc<Args>,
+    multi: bool,
+    credentials: Option<Credentials>,
+    statuses: NodeStatuses,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), PodError> {
+    statuses.set(&node.name, NodeState::Starting);
+
+    // Nodes sharing the pod must not share files
+    let node_dir = |dir: &PathBuf| match multi {
+        true => dir.join(&node.name),
+        false => dir.clone(),
+    };
+    let node_path = |path: &PathBuf| match multi {
+        true => node.path(path),
+        false => path.clone(),
+    };
+
+    let installer = args
+        .core_dir
+        .as_ref()
+        .map(|dir| {
+            Installer::new(InstallOptions {
+                dir: node_dir(dir),
+                public_key: args.core_public_key.clone(),
+                mirror: args.core_mirror.clone(),
+            })
+        })
+        .transpose()
+        .map_err(PodError::CoreDir)?;
+
+    let runtime_dir = args
+        .runtime_dir
+        .clone()
+        .or_else(default_runtime_dir)
+        .as_ref()
+        .map(node_dir);
+    if args.adopt && runtime_dir.is_none() {
+        warn!("No runtime dir outlives the pod, pass --runtime-dir to adopt sing-box");
+    }
+    let log_file_enabled = args.singbox_log_file.is_some();
+    // sing-box logs through a FIFO that a later pod instance can reopen
+    let log_pipe = args
+        .adopt
+        .then(|| {
+            runtime_dir
+                .as_ref()
+                .map(|dir| dir.join("sing-box.log.pipe"))
+        })
+        .flatten();
+
+    // An adopted sing-box only serves stats on the address it was started with
+    let v2ray_api_listen = runtime_dir
+        .as_ref()
+        .filter(|_| args.adopt)
+        .and_then(|dir| PidFile::read(&PidFile::path(&dir.join(config::RUNTIME_FILE))))
+        .filter(PidFile::is_alive)
+        .and_then(|orphan| orphan.stats_listen());
+
+    // Initialize components, the first fetch tells which core the node runs
+    let mut config = config::ConfigManager::new(
+        fetch.clone(),
+        config::ConfigOptions {
+            runtime_dir,
+            log_level: args.singbox_log_level.clone(),
+            capture_log: log_file_enabled,
+            log_pipe: log_pipe.clone(),
+            core_version: None,
+            owner: credentials,
+            reuse_addr: args.drain_period.is_some(),
+            v2ray_api_listen,
+        },
+    )
+    .await?;
+    let core = config.core();
+
+    let log_file = args.singbox_log_file.as_ref().map(|path| LogFileOptions {
+        path: node_path(path),
+        max_size: args.singbox_log_max_size,
+        max_files: args.singbox_log_max_files,
+    });
+    // A managed install replaced --singbox-bin, going back would downgrade
+    let binary = match core.kind() {
+        CoreKind::SingBox => installer
+            .as_ref()
+            .and_then(Installer::current)
+            .or_else(|| args.singbox_bin.clone()),
+        CoreKind::Xray => args.xray_bin.clone(),
+    };
+    let mut process_options = ProcessOptions {
+        core: core.clone(),
+        binary,
+        args: args.singbox_arg.clone(),
+        working_dir: args.singbox_dir.clone(),
+        env: args.singbox_env.clone(),
+        logout: log_file.is_none(),
+        log_file,
+        stop_timeout: Duration::from_secs(args.stop_timeout),
+        ready_timeout: Duration::from_secs(args.ready_timeout),
+        error_log: ErrorLogOptions {
+            max_entries: args.logs_max_entries,
+            redact_passwords: args.logs_redact_passwords,
+            redact_ips: args.logs_redact_ips,
+        },
+        limits: ResourceLimits {
+            nofile: args.rlimit_nofile,
+            address_space: args.rlimit_as,
+            core: args.rlimit_core,
+            nice: args.nice,
+            cgroup: args.cgroup.as_ref().map(|path| CgroupOptions {
+                path: node_path(path),
+                memory_max: args.cgroup_memory_max,
+                cpu_max: args.cgroup_cpu_max.clone(),
+            }),
+        },
+        credentials,
+        adopt: args.adopt,
+        log_pipe: log_pipe.clone(),
+        auto_restart: args.auto_restart,
+        restart_max_delay: Duration::from_secs(args.restart_max_delay),
+        drain_period: args.drain_period.map(Duration::from_secs),
+    };
+
+    // Resolve the core binary and what it supports, preferring a managed install
+    let binary = process_options
+        .resolve_binary()
+        .inspect_err(|e| error!("{}", e))?;
+    info!("Using {} binary: {}", core.kind(), binary.display());
+
+    if core.kind() == CoreKind::SingBox {
+        match SingBoxVersion::detect(&binary).await {
+            Ok(version) => {
+                info!(
+                    "sing-box version {} (tags: {})",
+                    version.version,
+                    version.tags.join(",")
+                );
+                config.set_core_version(version);
+                // Sharing the ports with the next sing-box needs reuse_addr
+                if process_options.drain_period.is_some() && !config.reuse_addr_enabled() {
+                    warn!(
+                        "sing-box {} predates reuse_addr, blue/green restarts are disabled",
+                        config.core_version().unwrap().version
+                    );
+                    process_options.drain_period = None;
+                }
+                // Leave out what this sing-box was built without
+                config.rebuild()?;
+            }
+            Err(e) => warn!("Failed to detect sing-box version: {}", e),
+        }
+    }
+
+    // Setup process manager
+    let manager = setup_process_manager(&config, process_options).await?;
+
+    // sing-box is listening on the API endpoint once started
+    let v2ray_api = if config.stats_enabled() {
+        // An adopted sing-box may run a blue/green copy of the config
+        let v2ray_api_endpoint = manager
+            .stats_endpoint()
+            .unwrap_or_else(|| config.v2ray_api_endpoint.clone());
+        let api = V2rayApi::new(
+            format!("http://{}", v2ray_api_endpoint),
+            core.stats_package(),
+        )
+        .await;
+        match api {
+            Ok(api) => Some(api),
+            Err(e) => {
+                // The next attempt starts its own sing-box
+                shutdown_manager(&manager, args.adopt).await;
+                return Err(e.into());
+            }
+        }
+    } else {
+        warn!("sing-box was built without with_v2ray_api, traffic stats are disabled");
+        None
+    };
+
+    let manager_arc = Arc::new(manager);
+    let supervisor = manager_arc.supervise();
+    if let Some(pid) = manager_arc.pid().await {
+        statuses.set(&node.name, NodeState::Running { pid });
+    }
+    let status_handle = task::spawn(
+        track_node_state(node.name.clone(), manager_arc.subscribe(), statuses.clone())
+            .in_current_span(),
+    );
+
+    // Run reporting tasks concurrently (producer + consumer)
+    let scheduler = ReloadScheduler::new(Duration::from_secs(args.user_reload_delay));
+    let reporting_handle = spawn_reporting_tasks(
+        config,
+        fetch,
+        v2ray_api,
+        Arc::clone(&manager_arc),
+        scheduler,
+        installer,
+        statuses.node(&node.name),
+    );
+
+    // Run until the pod shuts down
+    tokio::select! {
+        res = reporting_handle => {
+            if let Err(e) = res {
+                error!("Reporting task error: {}", e);
+            }
+        }
+        _ = shutdown.changed() => {}
+    }
+
+    // Cleanup
+    shutdown_manager(&manager_arc, args.adopt).await;
+    supervisor.abort();
+    status_handle.abort();
+    statuses.set(&node.name, NodeState::Stopped);
+
+    Ok(())
+}
+
+/// Runs every node of the pod until they all ended or `shutdown` resolves,
+/// then stops their sing-box
+pub async fn run(args: Args, shutdown: impl Future<Output = ()>) -> Result<(), PodError> {
+    let credentials = args
+        .singbox_user
+        .as_deref()
+        .map(|user| Credentials::lookup(user, args.singbox_group.as_deref()))
+        .transpose()
+        .inspect_err(|e| error!("Failed to look up sing-box user: {}", e))
+        .map_err(PodError::Credentials)?;
+
+    let multi = args.nodes_file.is_some();
+    let nodes = match &args.nodes_file {
+        Some(path) => NodeSpec::load(path)?,
+        // clap requires --url and --auth without a nodes file
+        None => {
+            let nodes = vec![NodeSpec {
+                name: "default".to_string(),
+                url: args.url.clone().unwrap_or_default(),
+                auth: args.auth.clone().unwrap_or_default(),
+                logs_url: args.logs_url.clone(),
+            }];
+            NodeSpec::validate(&nodes)?;
+            nodes
+        }
+    };
+
+    let args = Arc::new(args);
+    let statuses = NodeStatuses::default();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let mut runners = JoinSet::new();
+    let mut names = std::collections::HashMap::new();
+    for node in nodes {
+        let name = node.name.clone();
+        // A single node keeps the log lines of a one-node pod
+        let span = match multi {
+            true => info_span!("node", name = %name),
+            false => Span::none(),
+        };
+        let runner = run_node(
+            node,
+            Arc::clone(&args),
+            multi,
+            credentials,
+            statuses.clone(),
+            shutdown_rx.clone(),
+        );
+        let handle = runners.spawn(runner.instrument(span));
+        names.insert(handle.id(), name);
+    }
+
+    let status_logger = multi.then(|| task::spawn(log_node_statuses(statuses.clone())));
+
+    // Run until every node ended or a shutdown signal arrives
+    tokio::pin!(shutdown);
+    let mut failed = 0;
+    loop {
+        tokio::select! {
+            joined = runners.join_next_with_id() => {
+                let e = match joined {
+                    None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => e,
+                };
+                let name = &names[&e.id()];
+                error!("Node {} failed: {}", name, e);
+                statuses.set(name, NodeState::Failed { error: e.to_string() });
+                failed += 1;
+            }
+            _ = &mut shutdown => {
+                let _ = shutdown_tx.send(true);
+                while runners.join_next().await.is_some() {}
+                break;
+            }
+        }
+    }
+
+    if let Some(status_logger) = status_logger {
+        status_logger.abort();
+        info!("Nodes: {}", statuses.summary());
+    }
+    info!("Program exit");
+
+    // Nodes fail independently, the pod only fails when none is left
+    if failed > 0 && failed == names.len() {
+        return Err(PodError::NodesFailed {
+            failed,
+            total: names.len(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_parse_mib() {
+        let args = |extra: &[&str]| {
+            let argv = ["next-proxies-pod", "--url=http://panel", "--auth=a"];
+            Args::try_parse_from(argv.iter().chain(extra))
+        };
+
+        let parsed = args(&["--rlimit-as=512", "--rlimit-core=0"]).unwrap();
+        assert_eq!(parsed.rlimit_as, Some(512 * 1024 * 1024));
+        assert_eq!(parsed.rlimit_core, Some(0));
+        assert_eq!(parsed.singbox_log_max_size, 10 * 1024 * 1024);
+
+        assert!(args(&["--rlimit-as=18446744073709551615"]).is_err());
+        assert!(args(&["--singbox-log-max-size=17592186044416"]).is_err());
+    }
+
+    #[test]
+    fn test_failed_state() {
+        let rejected = PodError::Config(config::ConfigError::Panel(PanelError::Status {
+            status: StatusCode::FORBIDDEN,
+            headers: Vec::new(),
+            body: "(empty body)".to_string(),
+        }));
+        assert_eq!(
+            failed_state(&rejected),
+            NodeState::AuthFailed {
+                status: StatusCode::FORBIDDEN
+            }
+        );
+
+        let backoff = PodError::Config(config::ConfigError::Panel(PanelError::AuthBackoff {
+            status: StatusCode::UNAUTHORIZED,
+            retry_in: Duration::from_secs(60),
+        }));
+        assert_eq!(
+            failed_state(&backoff),
+            NodeState::AuthFailed {
+                status: StatusCode::UNAUTHORIZED
+            }
+        );
+
+        let other = PodError::Process(ProcessError::NotRunning {
+            core: CoreKind::Xray,
+        });
+        assert_eq!(
+            failed_state(&other),
+            NodeState::Failed {
+                error: "no running xray process found".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use tracing::{error, info, warn};
+
+use super::{Exited, PidFile, ProcessError, ProcessEvent, ProcessManager};
+use crate::api::v2ray_api::{StatsError, StatsFormatResponse, V2rayApi};
+use crate::config::write_atomic;
+
+/// Previous process of a blue/green restart, serving its connections until
+/// the drain period is over.
+pub(super) struct Draining {
+    pub pid: u32,
+    pub exited: tokio::sync::watch::Receiver<Option<Exited>>,
+}
+
+impl ProcessManager {
+    /// Whether binary upgrades and structural config changes go through
+    /// `blue_green_restart`. The inbounds must have been written with
+    /// `reuse_addr`, see `ConfigOptions::reuse_addr`.
+    pub fn blue_green_enabled(&self) -> bool {
+        cfg!(unix) && self.options.drain_period.is_some() && self.options.core.shares_ports()
+    }
+
+    /// Replaces the running sing-box without dropping its connections. The
+    /// new process is started from a copy of the config with its own v2ray
+    /// API port and shares the inbound ports with the old one. Once it is
+    /// ready, the old process keeps serving its connections for the drain
+    /// period before it is terminated.
+    ///
+    /// The old process stops listening before the drain period, so new
+    /// connections only go to the new one, see `stop_listening`. UDP
+    /// inbounds stay shared until the old process is terminated. Its traffic
+    /// is queried one last time before, see `take_unreported_stats`. If the
+    /// new process doesn't get ready, it is terminated and the old one keeps
+    /// running.
+    pub async fn blue_green_restart(&self) -> Result<(), ProcessError> {
+        // The next restart would otherwise reuse the draining process's config
+        let _guard = self.blue_green.lock().await;
+        self.blue_green_restart_locked().await
+    }
+
+    /// Runs `blue_green_restart` in the background. Requests made while one
+    /// waits for the running restart are merged into it, it picks up the
+    /// latest config anyway.
+    pub fn spawn_blue_green_restart(&self) {
+        if self.blue_green_queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let _guard = manager.blue_green.lock().await;
+            manager.blue_green_queued.store(false, Ordering::Release);
+            if let Err(e) = manager.blue_green_restart_locked().await {
+                error!("Error restarting sing-box with the new config: {}", e);
+            }
+        });
+    }
+
+    async fn blue_green_restart_locked(&self) -> Result<(), ProcessError> {
+        let old_pid = *self.pid.lock().await;
+        let old_exited = self.exited.lock().await.clone();
+        let (Some(old_pid), Some(old_exited)) = (old_pid, old_exited) else {
+            return self.start().await;
+        };
+        let old_config = self.active_config.lock().clone();
+        let old_adopted = self.is_adopted();
+
+        let config = self.next_instance_config(&old_config);
+        let api_listen = match self.api_listen(&old_config) {
+            Some(_) => Some(format!("localhost:{}", pick_port()?)),
+            None => None,
+        };
+        self.write_instance_config(&config, api_listen.as_deref())?;
+
+        info!(
+            "Starting a new sing-box alongside the running one (pid={})",
+            old_pid
+        );
+        self.emit(ProcessEvent::Starting);
+
+        let mut instance = match self.spawn(&config).await {
+            Ok(instance) => instance,
+            Err(e) => {
+                self.restore(old_pid, old_exited, &old_config).await;
+                let _ = fs::remove_file(&config);
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = self
+            .wait_ready(
+                &config,
+                &instance.exited,
+                instance.stderr_task.as_mut(),
+                instance.pid,
+            )
+            .await
+        {
+            // Hand back to the old process before the new one's exit is seen
+            self.restore(old_pid, old_exited, &old_config).await;
+            self.adopted.store(old_adopted, Ordering::Relaxed);
+            if let Some(pid) = instance.pid {
+                let _ = self.terminate(pid, instance.exited).await;
+            }
+            let _ = fs::remove_file(&config);
+            return Err(e);
+        }
+
+        *self.active_config.lock() = config;
+        self.adopted.store(false, Ordering::Relaxed);
+        if let Some(pid) = instance.pid {
+            self.emit(ProcessEvent::Started { pid });
+        }
+
+        // Out of the SO_REUSEPORT group, the kernel would keep balancing new
+        // connections to the old process. Its v2ray API is still queried.
+        let api_port = self
+            .api_listen(&old_config)
+            .and_then(|listen| addr_port(&listen));
+        if let Err(e) = stop_listening(old_pid, api_port) {
+            warn!(
+                "Failed to stop the previous sing-box (pid={}) from accepting connections: {}",
+                old_pid, e
+            );
+        }
+
+        let drain_period = self.options.drain_period.unwrap_or_default();
+        info!(
+            "Draining the previous sing-box (pid={}) for {}s",
+            old_pid,
+            drain_period.as_secs()
+        );
+        // The pid file only records the new process, `stop_draining` ends
+        // this one if the pod shuts down meanwhile
+        *self.draining.lock() = Some(Draining {
+            pid: old_pid,
+            exited: old_exited,
+        });
+        tokio::time::sleep(drain_period).await;
+        let Some(Draining {
+            pid: old_pid,
+            exited: old_exited,
+        }) = self.draining.lock().take()
+        else {
+            return Ok(());
+        };
+
+        // Traffic on the drained connections would be lost with the process
+        if let Some(api_listen) = self.api_listen(&old_config) {
+            match self.query_stats(&api_listen).await {
+                Ok(stats) => self.restore_stats(stats),
+                Err(e) => warn!(
+                    "Failed to query the stats of the previous sing-box (pid={}): {}",
+                    old_pid, e
+                ),
+            }
+        }
+
+        let status = self.terminate(old_pid, old_exited).await;
+        if old_config != self.config_path {
+            let _ = fs::remove_file(&old_config);
+        }
+        info!("Previous sing-box (pid={}) drained", old_pid);
+
+        status.map(|_| ())
+    }
+
+    /// Terminates the previous process of a blue/green restart that is still
+    /// draining.
+    pub async fn stop_draining(&self) {
+        let Some(Draining { pid, exited }) = self.draining.lock().take() else {
+            return;
+        };
+
+        info!("Stopping the draining sing-box (pid={})", pid);
+        if let Err(e) = self.terminate(pid, exited).await {
+            warn!("Failed to stop the draining sing-box (pid={}): {}", pid, e);
+        }
+    }
+
+    /// Pid of the previous process of a blue/green restart while it drains.
+    pub fn draining_pid(&self) -> Option<u32> {
+        self.draining.lock().as_ref().map(|draining| draining.pid)
+    }
+
+    async fn query_stats(&self, api_listen: &str) -> Result<StatsFormatResponse, StatsError> {
+        let mut api = V2rayApi::new(
+            format!("http://{}", api_listen),
+            self.options.core.stats_package(),
+        )
+        .await?;
+        api.query_all_stats(true).await
+    }
+
+    /// Makes `pid` the current process again after a failed blue/green restart.
+    async fn restore(
+        &self,
+        pid: u32,
+        exited: tokio::sync::watch::Receiver<Option<Exited>>,
+        config: &Path,
+    ) {
+        *self.pid.lock().await = Some(pid);
+        *self.exited.lock().await = Some(exited);
+        *self.active_config.lock() = config.to_path_buf();
+
+        let pid_file = self.pid_file_path();
+        if let Err(e) = PidFile::new(pid, config.to_path_buf()).write(&pid_file) {
+            warn!("Failed to write pid file {}: {}", pid_file.display(), e);
+        }
+    }
+
+    /// v2ray API endpoint of the current process, which differs from the
+    /// config manager's after a blue/green restart.
+    pub fn stats_endpoint(&self) -> Option<String> {
+        self.api_listen(&self.active_config.lock())
+    }
+
+    /// Copies `config_path` to the config the current process runs, keeping
+    /// its v2ray API port, so a reload picks up the latest config.
+    pub(super) fn refresh_active_config(&self) -> io::Result<()> {
+        let active = self.active_config.lock().clone();
+        if active == self.config_path {
+            return Ok(());
+        }
+
+        self.write_instance_config(&active, self.api_listen(&active).as_deref())
+    }
+
+    /// Goes back to running `config_path`, removing the copy used so far.
+    pub(super) fn reset_active_config(&self) {
+        let previous = std::mem::replace(&mut *self.active_config.lock(), self.config_path.clone());
+        if previous != self.config_path {
+            let _ = fs::remove_file(previous);
+        }
+    }
+
+    /// Whether `config` is `config_path` or one of its blue/green copies.
+    pub(super) fn is_instance_config(&self, config: &Path) -> bool {
+        config == self.config_path
+            || config == instance_config_path(&self.config_path, "blue")
+            || config == instance_config_path(&self.config_path, "green")
+    }
+
+    /// Alternates between two copies, so the next never overwrites the
+    /// config of the running process.
+    fn next_instance_config(&self, active: &Path) -> PathBuf {
+        let green = instance_config_path(&self.config_path, "green");
+        if active == green {
+            instance_config_path(&self.config_path, "blue")
+        } else {
+            green
+        }
+    }
+
+    /// Reads the v2ray API endpoint of a config.
+    fn api_listen(&self, config: &Path) -> Option<String> {
+        let config: serde_json::Value = serde_json::from_slice(&fs::read(config).ok()?).ok()?;
+        config
+            .pointer(self.options.core.stats_listen_pointer())?
+            .as_str()
+            .map(str::to_string)
+    }
+
+    /// Writes `config_path` to `path` with the v2ray API on `api_listen`.
+    fn write_instance_config(&self, path: &Path, api_listen: Option<&str>) -> io::Result<()> {
+        let mut config: serde_json::Value = serde_json::from_slice(&fs::read(&self.config_path)?)?;
+        if let Some(api_listen) = api_listen
+            && let Some(listen) = config.pointer_mut(self.options.core.stats_listen_pointer())
+        {
+            *listen = api_listen.into();
+        }
+
+        write_atomic(
+            path,
+            &serde_json::to_vec(&config)?,
+            self.options.credentials.as_ref(),
+        )
+    }
+}
+
+/// `singbox-runtime.json` becomes `singbox-runtime.<color>.json`.
+fn instance_config_path(config_path: &Path, color: &str) -> PathBuf {
+    let stem = config_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let name = match config_path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, color, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, color),
+    };
+    config_path.with_file_name(name)
+}
+
+fn pick_port() -> io::Result<u16> {
+    portpicker::pick_unused_port()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "no free port for the v2ray API"))
+}
+
+/// Port of a `host:port` address.
+pub(super) fn addr_port(addr: &str) -> Option<u16> {
+    addr.rsplit_once(':')?.1.parse().ok()
+}
+
+/// TCP ports `pid` listens on, read from procfs. `None` if they can't be
+/// told apart from other processes' on this platform.
+#[cfg(target_os = "linux")]
+pub(super) fn listening_ports(pid: u32) -> Option<HashSet<u16>> {
+    Some(listening_sockets(pid)?.into_values().collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn listening_ports(_pid: u32) -> Option<HashSet<u16>> {
+    None
+}
+
+/// Shuts down the listening TCP sockets of `pid` but the one on `keep`
+/// through copies of its fds, which takes them out of their SO_REUSEPORT
+/// group while the connections they accepted keep going. Needs Linux 5.6 and
+/// ptrace access to `pid`.
+#[cfg(target_os = "linux")]
+fn stop_listening(pid: u32, keep: Option<u16>) -> io::Result<()> {
+    use nix::libc;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let listening = listening_sockets(pid)
+        .ok_or_else(|| io::Error::other(format!("no fd table for pid {}", pid)))?;
+
+    let check = |result: libc::c_long| match result {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) }),
+    };
+
+    let pidfd = check(unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) })?;
+    for (fd, port) in listening {
+        if Some(port) == keep {
+            continue;
+        }
+        let socket =
+            check(unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) })?;
+        if unsafe { libc::shutdown(socket.as_raw_fd(), libc::SHUT_RD) } == -1 {
+            let e = io::Error::last_os_error();
+            return Err(io::Error::new(e.kind(), format!("port {}: {}", port, e)));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stop_listening(_pid: u32, _keep: Option<u16>) -> io::Result<()> {
+    Ok(())
+}
+
+/// Listening TCP sockets of `pid` by fd, with their port.
+#[cfg(target_os = "linux")]
+fn listening_sockets(pid: u32) -> Option<HashMap<i32, u16>> {
+    // Sockets show up as `socket:[<inode>]` links in the fd table
+    let fds: HashMap<u64, i32> = fs::read_dir(format!("/proc/{}/fd", pid))
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let fd = entry.file_name().to_str()?.parse().ok()?;
+            let link = fs::read_link(entry.path()).ok()?;
+            let inode = link
+                .to_str()?
+                .strip_prefix("socket:[")?
+                .strip_suffix(']')?
+                .parse()
+                .ok()?;
+            Some((inode, fd))
+        })
+        .collect();
+
+    let mut sockets = HashMap::new();
+    for table in ["tcp", "tcp6"] {
+        let Ok(content) = fs::read_to_string(format!("/proc/{}/net/{}", pid, table)) else {
+            continue;
+        };
+
+        // sl local_address rem_address st ... inode, 0A is LISTEN
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() > 9
+                && fields[3] == "0A"
+                && let Some(&fd) = fields[9].parse().ok().and_then(|inode| fds.get(&inode))
+                && let Some((_, port)) = fields[1].rsplit_once(':')
+                && let Ok(port) = u16::from_str_radix(port, 16)
+            {
+                sockets.insert(fd, port);
+            }
+        }
+    }
+
+    Some(sockets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_config_path() {
+        assert_eq!(
+            instance_config_path(Path::new("/run/pod/singbox-runtime.json"), "green"),
+            Path::new("/run/pod/singbox-runtime.green.json")
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_listening_ports() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let ports = listening_ports(std::process::id()).unwrap();
+        assert!(ports.contains(&port));
+    }
+}
@@ -0,0 +1,149 @@
+use reqwest::StatusCode;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::core::CoreKind;
+
+/// Failure to start, stop or reload the core process.
+#[derive(Debug, Error)]
+pub enum ProcessError {
+    #[error("{core} binary not found in the current directory or PATH, set it with {flag}")]
+    BinaryNotFound { core: CoreKind, flag: &'static str },
+
+    #[error("binary {} is not executable: {source}", binary.display())]
+    BinaryNotExecutable {
+        binary: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to spawn {}: {source}", binary.display())]
+    Spawn {
+        binary: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The process exited before listening on its ports, `stderr` holds its
+    /// last log lines.
+    #[error("{core} exited during startup with {}{}", exit_status(.status), stderr_tail(.stderr))]
+    ExitedDuringStartup {
+        core: CoreKind,
+        status: Option<ExitStatus>,
+        stderr: Vec<String>,
+    },
+
+    #[error(
+        "{} not listening on {} after {}s{}",
+        .core,
+        .pending.join(", "),
+        .timeout.as_secs(),
+        stderr_tail(.stderr)
+    )]
+    NotReady {
+        core: CoreKind,
+        pending: Vec<String>,
+        timeout: Duration,
+        stderr: Vec<String>,
+    },
+
+    #[error("{core} (pid={pid}) did not exit after being killed")]
+    StopTimeout { core: CoreKind, pid: u32 },
+
+    #[error("no running {core} process found")]
+    NotRunning { core: CoreKind },
+
+    #[error("failed to send {signal} to {core}: {source}")]
+    Signal {
+        core: CoreKind,
+        signal: &'static str,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Failure to download, verify or switch to a managed sing-box release.
+#[derive(Debug, Error)]
+pub enum InstallError {
+    #[error("invalid release version {0:?}")]
+    InvalidVersion(String),
+
+    #[error("another upgrade is in progress")]
+    Busy,
+
+    #[error("failed to download {url}: {source}")]
+    Download {
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+
+    #[error("failed to download {url}: HTTP {status}")]
+    Status { url: String, status: StatusCode },
+
+    #[error("sha256 mismatch: expected {expected}, got {actual}")]
+    Checksum { expected: String, actual: String },
+
+    #[error("signature check failed: {0}")]
+    Signature(#[from] minisign_verify::Error),
+
+    #[error("a public key is configured but the release is not signed")]
+    Unsigned,
+
+    #[error("archive does not contain {0}")]
+    MissingBinary(&'static str),
+
+    #[error("archive for {expected} contains sing-box {actual}")]
+    VersionMismatch { expected: String, actual: String },
+
+    #[error(transparent)]
+    Process(#[from] ProcessError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl InstallError {
+    /// Whether the release itself is bad: it failed verification or didn't
+    /// start. Other errors may pass on a later attempt.
+    pub fn rejects_release(&self) -> bool {
+        matches!(
+            self,
+            InstallError::InvalidVersion(_)
+                | InstallError::Checksum { .. }
+                | InstallError::Signature(_)
+                | InstallError::Unsigned
+                | InstallError::MissingBinary(_)
+                | InstallError::VersionMismatch { .. }
+                | InstallError::Process(_)
+        )
+    }
+
+    /// Whether downloading again right away may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            InstallError::Download { .. } => true,
+            InstallError::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+}
+
+fn exit_status(status: &Option<ExitStatus>) -> String {
+    status.map_or("unknown status".to_string(), |status| status.to_string())
+}
+
+fn stderr_tail(stderr: &[String]) -> String {
+    match stderr.is_empty() {
+        true => String::new(),
+        false => format!(", stderr:\n{}", stderr.join("\n")),
+    }
+}
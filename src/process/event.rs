@@ -0,0 +1,24 @@
+use std::process::ExitStatus;
+
+/// Lifecycle of the sing-box process, published by `ProcessManager`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProcessEvent {
+    /// A process is about to be spawned.
+    Starting,
+
+    /// The process is running and listening on its ports, either spawned or
+    /// adopted from a previous pod instance.
+    Started { pid: u32 },
+
+    /// The process was told to reload its config.
+    Reloaded,
+
+    /// The process is gone. The status is unknown for an adopted process.
+    Exited { status: Option<ExitStatus> },
+
+    /// The process exited unexpectedly and is restarted after a backoff.
+    Restarting { attempt: u32 },
+
+    /// The process was stopped on request.
+    Stopped,
+}
@@ -0,0 +1,521 @@
+use flate2::read::GzDecoder;
+use minisign_verify::{PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use super::ProcessManager;
+use super::error::InstallError;
+use super::version::SingBoxVersion;
+use crate::config::CoreRelease;
+use crate::core::sing_box::BINARY_NAME;
+
+/// File marking a version directory as created by the installer, the only
+/// ones it removes from the install dir.
+const MARKER: &str = ".pod-install";
+
+/// Attempts at downloading an archive in one upgrade.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+/// Delay before the first download retry, doubled after each attempt.
+const DOWNLOAD_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct InstallOptions {
+    /// Directory holding one sub-directory per installed version.
+    pub dir: PathBuf,
+
+    /// minisign public key release archives must be signed with.
+    pub public_key: Option<String>,
+
+    /// Base URL or directory archives are fetched from instead of the
+    /// panel-supplied location, keeping the archive file name.
+    pub mirror: Option<String>,
+}
+
+/// Installed versions, persisted as `state.json` in the install dir.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct InstallState {
+    current: Option<String>,
+    previous: Option<String>,
+}
+
+/// Installs and upgrades the sing-box binary the pod runs.
+///
+/// Releases are unpacked into `<dir>/<version>/sing-box`. The active and the
+/// previous version are kept so that a failed upgrade can roll back, other
+/// directories the installer created are removed.
+///
+/// Clones share their state, so an upgrade can run in its own task.
+#[derive(Clone)]
+pub struct Installer {
+    options: InstallOptions,
+    client: reqwest::Client,
+    /// Versions that failed verification or to start, not retried until
+    /// restart.
+    failed: Arc<parking_lot::Mutex<HashSet<String>>>,
+    upgrading: Arc<AtomicBool>,
+    /// Version of the last successful upgrade, until `take_upgraded`.
+    upgraded: Arc<parking_lot::Mutex<Option<SingBoxVersion>>>,
+}
+
+impl Installer {
+    pub fn new(options: InstallOptions) -> io::Result<Self> {
+        fs::create_dir_all(&options.dir)?;
+
+        Ok(Self {
+            options,
+            client: reqwest::Client::new(),
+            failed: Arc::default(),
+            upgrading: Arc::default(),
+            upgraded: Arc::default(),
+        })
+    }
+
+    /// Binary of the active version, if any was installed.
+    pub fn current(&self) -> Option<PathBuf> {
+        let version = self.read_state().current?;
+        let binary = self.binary_path(&version);
+        binary.is_file().then_some(binary)
+    }
+
+    /// Whether `release` should be installed over the running `current` version.
+    pub fn wants(&self, release: &CoreRelease, current: Option<&SingBoxVersion>) -> bool {
+        !self.upgrading.load(Ordering::Relaxed)
+            && !self.failed.lock().contains(&release.version)
+            && current.is_none_or(|current| current.version != release.version)
+    }
+
+    /// Version sing-box was upgraded to since the last call.
+    pub fn take_upgraded(&self) -> Option<SingBoxVersion> {
+        self.upgraded.lock().take()
+    }
+
+    /// Installs `release` and restarts sing-box with it, rolling back to the
+    /// previous binary if the new one fails to start. Only one upgrade runs
+    /// at a time, a concurrent one fails with `InstallError::Busy`.
+    pub async fn upgrade(
+        &self,
+        manager: &ProcessManager,
+        release: &CoreRelease,
+    ) -> Result<SingBoxVersion, InstallError> {
+        if self.upgrading.swap(true, Ordering::AcqRel) {
+            return Err(InstallError::Busy);
+        }
+
+        let result = self.try_upgrade(manager, release).await;
+        match &result {
+            Ok(version) => *self.upgraded.lock() = Some(version.clone()),
+            // A failed download is tried again on the next cycle
+            Err(e) if e.rejects_release() => {
+                self.failed.lock().insert(release.version.clone());
+            }
+            Err(_) => {}
+        }
+
+        self.upgrading.store(false, Ordering::Release);
+        result
+    }
+
+    async fn try_upgrade(
+        &self,
+        manager: &ProcessManager,
+        release: &CoreRelease,
+    ) -> Result<SingBoxVersion, InstallError> {
+        let binary = self.install(release).await?;
+
+        let version = SingBoxVersion::detect(&binary).await?;
+        if version.version != release.version {
+            return Err(InstallError::VersionMismatch {
+                expected: release.version.clone(),
+                actual: version.version,
+            });
+        }
+
+        let previous = manager.binary().ok();
+        self.activate(&release.version)?;
+
+        if let Err(e) = manager.restart_with(binary).await {
+            error!("sing-box {} failed to start: {}", release.version, e);
+
+            let rollback = self.rollback()?.or(previous);
+            // A blue/green restart leaves the previous sing-box running
+            if manager.is_running().await {
+                warn!("Keeping the running sing-box");
+            } else if let Some(rollback) = rollback {
+                warn!("Rolling back to {}", rollback.display());
+                manager.restart_with(rollback).await?;
+            }
+
+            return Err(e.into());
+        }
+
+        Ok(version)
+    }
+
+    /// Downloads, verifies and unpacks `release`, returning its binary.
+    pub async fn install(&self, release: &CoreRelease) -> Result<PathBuf, InstallError> {
+        // The version names a directory, it must not lead out of the install dir
+        if !is_valid_version(&release.version) {
+            return Err(InstallError::InvalidVersion(release.version.clone()));
+        }
+
+        let binary = self.binary_path(&release.version);
+        if binary.is_file() {
+            return Ok(binary);
+        }
+
+        let archive = self.download(&self.source(&release.url)).await?;
+
+        verify_sha256(&archive, &release.sha256)?;
+        self.verify_signature(&archive, release.signature.as_deref())?;
+
+        let version_dir = self.options.dir.join(&release.version);
+        let tmp_dir = self.options.dir.join(format!("{}.tmp", release.version));
+
+        tokio::task::spawn_blocking(move || {
+            if tmp_dir.exists() {
+                fs::remove_dir_all(&tmp_dir)?;
+            }
+            fs::create_dir_all(&tmp_dir)?;
+            fs::write(tmp_dir.join(MARKER), b"")?;
+            unpack_binary(&archive, &tmp_dir.join(BINARY_NAME))?;
+            Ok::<_, InstallError>(fs::rename(&tmp_dir, &version_dir)?)
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        info!(
+            "Installed sing-box {} to {}",
+            release.version,
+            binary.display()
+        );
+
+        Ok(binary)
+    }
+
+    /// Marks `version` as active, keeping the active one for rollback and
+    /// removing every other version the installer created.
+    fn activate(&self, version: &str) -> io::Result<()> {
+        let state = self.read_state();
+        let state = InstallState {
+            previous: state.current.filter(|current| current != version),
+            current: Some(version.to_string()),
+        };
+        self.write_state(&state)?;
+
+        for entry in fs::read_dir(&self.options.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let keep =
+                Some(&name) == state.current.as_ref() || Some(&name) == state.previous.as_ref();
+            if entry.file_type()?.is_dir() && !keep && entry.path().join(MARKER).is_file() {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes the previous version active again, returning its binary.
+    fn rollback(&self) -> io::Result<Option<PathBuf>> {
+        let state = self.read_state();
+        let Some(previous) = state.previous else {
+            return Ok(None);
+        };
+
+        self.write_state(&InstallState {
+            current: Some(previous.clone()),
+            previous: None,
+        })?;
+
+        Ok(Some(self.binary_path(&previous)))
+    }
+
+    fn source(&self, url: &str) -> String {
+        match &self.options.mirror {
+            Some(mirror) => {
+                let file_name = url.rsplit('/').next().unwrap_or(url);
+                format!("{}/{}", mirror.trim_end_matches('/'), file_name)
+            }
+            None => url.to_string(),
+        }
+    }
+
+    /// Fetches the archive at `source`, retrying with backoff while that
+    /// fails in a way that may pass.
+    async fn download(&self, source: &str) -> Result<Vec<u8>, InstallError> {
+        let mut delay = DOWNLOAD_RETRY_DELAY;
+        for _ in 1..DOWNLOAD_ATTEMPTS {
+            match self.fetch_archive(source).await {
+                Err(e) if e.is_transient() => {
+                    warn!("{}, retrying in {}s", e, delay.as_secs());
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+
+        self.fetch_archive(source).await
+    }
+
+    async fn fetch_archive(&self, source: &str) -> Result<Vec<u8>, InstallError> {
+        if !source.starts_with("http://") && !source.starts_with("https://") {
+            let path = source.strip_prefix("file://").unwrap_or(source);
+            return Ok(tokio::fs::read(path).await?);
+        }
+
+        info!("Downloading sing-box from {}", source);
+
+        let download = |source_error| InstallError::Download {
+            url: source.to_string(),
+            source: source_error,
+        };
+        let response = self.client.get(source).send().await.map_err(download)?;
+        if !response.status().is_success() {
+            return Err(InstallError::Status {
+                url: source.to_string(),
+                status: response.status(),
+            });
+        }
+
+        Ok(response.bytes().await.map_err(download)?.to_vec())
+    }
+
+    fn verify_signature(
+        &self,
+        archive: &[u8],
+        signature: Option<&str>,
+    ) -> Result<(), InstallError> {
+        match (&self.options.public_key, signature) {
+            (Some(public_key), Some(signature)) => {
+                let public_key = PublicKey::from_base64(public_key)?;
+                let signature = Signature::decode(signature)?;
+                Ok(public_key.verify(archive, &signature, false)?)
+            }
+            (Some(_), None) => Err(InstallError::Unsigned),
+            (None, Some(_)) => {
+                warn!("Release is signed but no public key is configured, skipping check");
+                Ok(())
+            }
+            (None, None) => Ok(()),
+        }
+    }
+
+    fn binary_path(&self, version: &str) -> PathBuf {
+        self.options.dir.join(version).join(BINARY_NAME)
+    }
+
+    fn read_state(&self) -> InstallState {
+        fs::read_to_string(self.options.dir.join("state.json"))
+            .ok()
+            .and_then(|state| serde_json::from_str(&state).ok())
+            .unwrap_or_default()
+    }
+
+    fn write_state(&self, state: &InstallState) -> io::Result<()> {
+        let state = serde_json::to_string(state)?;
+        let tmp_path = self.options.dir.join("state.json.tmp");
+        fs::write(&tmp_path, state)?;
+        fs::rename(tmp_path, self.options.dir.join("state.json"))
+    }
+}
+
+/// Whether `version` is safe as a directory name: `[0-9A-Za-z.+-]`, starting
+/// with a letter or digit.
+fn is_valid_version(version: &str) -> bool {
+    version
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-'))
+}
+
+fn verify_sha256(archive: &[u8], expected: &str) -> Result<(), InstallError> {
+    let actual: String = Sha256::digest(archive)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(InstallError::Checksum {
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// Extracts the sing-box binary from a release tar.gz, which keeps it in a
+/// versioned sub-directory such as `sing-box-1.10.1-linux-amd64/sing-box`.
+fn unpack_binary(archive: &[u8], dest: &Path) -> Result<(), InstallError> {
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let is_binary =
+            entry.path()?.file_name().and_then(|name| name.to_str()) == Some(BINARY_NAME);
+
+        if is_binary && entry.header().entry_type().is_file() {
+            entry.unpack(dest)?;
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(dest, fs::Permissions::from_mode(0o755))?;
+            }
+
+            return Ok(());
+        }
+    }
+
+    Err(InstallError::MissingBinary(BINARY_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compression, write::GzEncoder};
+    use temp_dir::TempDir;
+
+    fn archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let content = b"#!/bin/sh\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(
+                &mut header,
+                format!("sing-box-1.10.1-linux-amd64/{}", BINARY_NAME),
+                &content[..],
+            )
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_install_from_local_archive() {
+        let dir = TempDir::new().unwrap();
+        let archive = archive();
+        let archive_path = dir.child("sing-box.tar.gz");
+        fs::write(&archive_path, &archive).unwrap();
+
+        let installer = Installer::new(InstallOptions {
+            dir: dir.child("core"),
+            public_key: None,
+            mirror: None,
+        })
+        .unwrap();
+
+        let mut release = CoreRelease {
+            version: "1.10.1".to_string(),
+            url: archive_path.to_string_lossy().to_string(),
+            sha256: "00".repeat(32),
+            signature: None,
+        };
+        assert!(matches!(
+            installer.install(&release).await,
+            Err(InstallError::Checksum { .. })
+        ));
+
+        release.sha256 = Sha256::digest(&archive)
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        let binary = installer.install(&release).await.unwrap();
+
+        assert_eq!(binary, dir.child("core").join("1.10.1").join(BINARY_NAME));
+        assert_eq!(fs::read(&binary).unwrap(), b"#!/bin/sh\n");
+        assert!(installer.current().is_none());
+
+        installer.activate("1.10.1").unwrap();
+        assert_eq!(installer.current(), Some(binary));
+    }
+
+    #[tokio::test]
+    async fn test_failed_upgrade() {
+        let dir = TempDir::new().unwrap();
+        let installer = Installer::new(InstallOptions {
+            dir: dir.child("core"),
+            public_key: None,
+            mirror: None,
+        })
+        .unwrap();
+        let manager = ProcessManager::new(dir.child("config.json"), Default::default());
+
+        // An archive that can't be fetched is tried again
+        let archive_path = dir.child("sing-box.tar.gz");
+        let release = CoreRelease {
+            version: "1.10.1".to_string(),
+            url: archive_path.to_string_lossy().to_string(),
+            sha256: "00".repeat(32),
+            signature: None,
+        };
+        assert!(matches!(
+            installer.upgrade(&manager, &release).await,
+            Err(InstallError::Io(_))
+        ));
+        assert!(installer.wants(&release, None));
+
+        // one that fails verification isn't
+        fs::write(&archive_path, archive()).unwrap();
+        assert!(matches!(
+            installer.upgrade(&manager, &release).await,
+            Err(InstallError::Checksum { .. })
+        ));
+        assert!(!installer.wants(&release, None));
+    }
+
+    #[tokio::test]
+    async fn test_install_stays_in_dir() {
+        let dir = TempDir::new().unwrap();
+        let archive = archive();
+        let archive_path = dir.child("sing-box.tar.gz");
+        fs::write(&archive_path, &archive).unwrap();
+
+        let installer = Installer::new(InstallOptions {
+            dir: dir.child("core"),
+            public_key: None,
+            mirror: None,
+        })
+        .unwrap();
+        fs::create_dir(dir.child("core").join("operator-files")).unwrap();
+
+        let release = |version: &str| CoreRelease {
+            version: version.to_string(),
+            url: archive_path.to_string_lossy().to_string(),
+            sha256: Sha256::digest(&archive)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            signature: None,
+        };
+        for version in ["../escaped", "/tmp/escaped", "..", ""] {
+            assert!(matches!(
+                installer.install(&release(version)).await,
+                Err(InstallError::InvalidVersion(_))
+            ));
+        }
+        assert!(!dir.child("escaped").exists());
+
+        for version in ["1.10.1", "1.10.2", "1.11.0-beta.1+build"] {
+            installer.install(&release(version)).await.unwrap();
+            installer.activate(version).unwrap();
+        }
+        assert!(!dir.child("core").join("1.10.1").exists());
+        assert!(dir.child("core").join("1.10.2").exists());
+        assert!(dir.child("core").join("operator-files").exists());
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::warn;
+
+/// Limits applied to the sing-box child before it execs.
+#[derive(Clone, Debug, Default)]
+pub struct ResourceLimits {
+    /// RLIMIT_NOFILE, the maximum number of open file descriptors.
+    pub nofile: Option<u64>,
+
+    /// RLIMIT_AS, the maximum address space in bytes.
+    pub address_space: Option<u64>,
+
+    /// RLIMIT_CORE, the maximum core dump size in bytes, 0 disables them.
+    pub core: Option<u64>,
+
+    /// Niceness the child runs with, set rather than added to the pod's.
+    pub nice: Option<i32>,
+
+    /// cgroup v2 the child is placed in.
+    pub cgroup: Option<CgroupOptions>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CgroupOptions {
+    /// cgroup directory, such as `/sys/fs/cgroup/pod/sing-box`. Created if
+    /// missing. Its parent must be delegated to the pod and must not contain
+    /// processes itself, as cgroup v2 only lets leaves hold processes once
+    /// controllers are enabled.
+    pub path: PathBuf,
+
+    /// `memory.max` in bytes.
+    pub memory_max: Option<u64>,
+
+    /// `cpu.max` as `<quota> <period>` in microseconds, or `max`.
+    pub cpu_max: Option<String>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.nofile.is_none()
+            && self.address_space.is_none()
+            && self.core.is_none()
+            && self.nice.is_none()
+            && self.cgroup.is_none()
+    }
+
+    /// Prepares the cgroup and makes the child apply the limits and join the
+    /// cgroup before exec, so sing-box never runs unconstrained.
+    #[cfg(unix)]
+    pub fn apply(&self, command: &mut Command) -> io::Result<()> {
+        use nix::libc;
+        use nix::sys::resource::{Resource, setrlimit};
+        use std::os::fd::AsRawFd;
+
+        // A pre_exec hook keeps std from spawning with posix_spawn
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let cgroup_procs = self
+            .cgroup
+            .as_ref()
+            .map(CgroupOptions::prepare)
+            .transpose()?;
+        let rlimits = [
+            (Resource::RLIMIT_NOFILE, self.nofile),
+            (Resource::RLIMIT_AS, self.address_space),
+            (Resource::RLIMIT_CORE, self.core),
+        ];
+        let nice = self.nice;
+
+        // Only async-signal-safe calls between fork and exec, nothing allocates
+        let pre_exec = move || {
+            for (resource, limit) in rlimits {
+                if let Some(limit) = limit {
+                    let limit = limit as libc::rlim_t;
+                    setrlimit(resource, limit, limit)?;
+                }
+            }
+
+            if let Some(nice) = nice
+                && unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Writing 0 to cgroup.procs moves the writing process
+            if let Some(procs) = &cgroup_procs
+                && unsafe { libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) } == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        };
+
+        unsafe { command.pre_exec(pre_exec) };
+
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    pub fn apply(&self, _command: &mut Command) -> io::Result<()> {
+        if !self.is_empty() {
+            warn!("Resource limits are not supported on Windows, ignoring them");
+        }
+        Ok(())
+    }
+}
+
+impl CgroupOptions {
+    /// Creates the cgroup, writes its limits and opens its `cgroup.procs`.
+    #[cfg(target_os = "linux")]
+    fn prepare(&self) -> io::Result<fs::File> {
+        fs::create_dir_all(&self.path).map_err(|e| cgroup_error(&self.path, e))?;
+
+        let controllers: Vec<&str> = [
+            self.memory_max.map(|_| "+memory"),
+            self.cpu_max.as_ref().map(|_| "+cpu"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if let Some(parent) = self.path.parent()
+            && !controllers.is_empty()
+            && let Err(e) = fs::write(parent.join("cgroup.subtree_control"), controllers.join(" "))
+        {
+            // Fine if the delegating manager already enabled them
+            warn!(
+                "Failed to enable {} in {}: {}",
+                controllers.join(" "),
+                parent.display(),
+                e
+            );
+        }
+
+        if let Some(memory_max) = self.memory_max {
+            write_cgroup_file(&self.path.join("memory.max"), &memory_max.to_string())?;
+        }
+        if let Some(cpu_max) = &self.cpu_max {
+            write_cgroup_file(&self.path.join("cpu.max"), cpu_max)?;
+        }
+
+        let procs = self.path.join("cgroup.procs");
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&procs)
+            .map_err(|e| cgroup_error(&procs, e))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn prepare(&self) -> io::Result<fs::File> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cgroups are only supported on Linux",
+        ))
+    }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn write_cgroup_file(path: &Path, value: &str) -> io::Result<()> {
+    fs::write(path, value).map_err(|e| cgroup_error(path, e))
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn cgroup_error(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("failed to set up cgroup at {}: {}", path.display(), e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_apply_rlimits() {
+        let limits = ResourceLimits {
+            nofile: Some(64),
+            core: Some(0),
+            ..Default::default()
+        };
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("ulimit -n; ulimit -c");
+        limits.apply(&mut command).unwrap();
+
+        let output = command.output().await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "64\n0\n");
+    }
+}
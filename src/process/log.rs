@@ -0,0 +1,568 @@
+use regex::{Captures, Regex};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Matches sing-box's log format once colors are stripped, where the
+/// timestamp, the seconds since start, the connection id and the component
+/// are optional. Files and `log.timestamp` get the first shape, stderr the
+/// second by default:
+///
+/// ```text
+/// +0800 2024-12-05 12:00:00 INFO [3735928559 12ms] inbound/shadowsocks[ss-in]: message
+/// INFO[0012] [3735928559 12ms] inbound/shadowsocks[ss-in]: message
+/// ```
+static LINE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?:(?P<timestamp>[+-]\d{4} \d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2})\s+)?(?P<level>TRACE|DEBUG|INFO|WARN|ERROR|FATAL|PANIC)(?:\[\d+\])?\s+(?:\[(?P<id>\d+)(?:\s+(?P<duration>[^\]]+))?\]\s+)?(?:(?P<component>[\w./-]+(?:\[[^\]]*\])?):\s+)?(?P<message>.*)$",
+    )
+    .unwrap()
+});
+
+/// Matches Xray's error log format, where the connection id and the
+/// component are optional:
+///
+/// ```text
+/// 2024/12/05 12:00:00.123456 [Info] [3735928559] proxy/shadowsocks: message
+/// ```
+static XRAY_LINE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^(?P<timestamp>\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2}(?:\.\d+)?)\s+\[(?P<level>Debug|Info|Warning|Error)\]\s+(?:\[(?P<id>\d+)\]\s+)?(?:(?P<component>[\w./-]+):\s+)?(?P<message>.*)$",
+    )
+    .unwrap()
+});
+
+/// ANSI color codes sing-box wraps levels and connection ids in on a terminal.
+static ANSI_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\x1b\[[0-9;]*m").unwrap());
+
+/// `ip:port` and `[ipv6]:port` pairs, the client's port changes with every
+/// connection.
+static ADDR_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\[(?P<v6>[0-9A-Fa-f:.]+)\]:\d+|(?P<v4>\d{1,3}(?:\.\d{1,3}){3}):\d+").unwrap()
+});
+
+/// Candidates for IP addresses, validated by parsing before being redacted.
+static IP_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[0-9A-Fa-f:.]*[:.][0-9A-Fa-f:.]*").unwrap());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+    Panic,
+}
+
+impl LogLevel {
+    fn parse(level: &str) -> Option<Self> {
+        match level {
+            "TRACE" => Some(Self::Trace),
+            "DEBUG" | "Debug" => Some(Self::Debug),
+            "INFO" | "Info" => Some(Self::Info),
+            "WARN" | "Warning" => Some(Self::Warn),
+            "ERROR" | "Error" => Some(Self::Error),
+            "FATAL" => Some(Self::Fatal),
+            "PANIC" => Some(Self::Panic),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Trace => "TRACE",
+            Self::Debug => "DEBUG",
+            Self::Info => "INFO",
+            Self::Warn => "WARN",
+            Self::Error => "ERROR",
+            Self::Fatal => "FATAL",
+            Self::Panic => "PANIC",
+        }
+    }
+}
+
+/// A line of sing-box or Xray output. Lines in neither log format, such as Go
+/// panics, only have a message.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogLine {
+    pub timestamp: Option<String>,
+    pub level: Option<LogLevel>,
+    pub component: Option<String>,
+    pub connection_id: Option<u64>,
+    pub duration: Option<String>,
+    pub message: String,
+}
+
+impl LogLine {
+    pub fn parse(line: &str) -> Self {
+        let line = ANSI_REGEX.replace_all(line, "");
+        let captures = LINE_REGEX
+            .captures(&line)
+            .or_else(|| XRAY_LINE_REGEX.captures(&line));
+        let Some(captures) = captures.as_ref() else {
+            return Self {
+                timestamp: None,
+                level: None,
+                component: None,
+                connection_id: None,
+                duration: None,
+                message: line.to_string(),
+            };
+        };
+
+        let capture = |name| captures.name(name).map(|m| m.as_str().to_string());
+
+        Self {
+            timestamp: capture("timestamp"),
+            level: LogLevel::parse(&captures["level"]),
+            component: capture("component"),
+            connection_id: captures.name("id").and_then(|id| id.as_str().parse().ok()),
+            duration: capture("duration"),
+            message: captures["message"].to_string(),
+        }
+    }
+
+    /// Re-emits the line as a tracing event with the parsed fields.
+    pub fn emit(&self) {
+        macro_rules! event {
+            ($level:ident) => {
+                tracing::$level!(
+                    target: "sing-box",
+                    timestamp = self.timestamp.as_deref(),
+                    component = self.component.as_deref(),
+                    connection_id = self.connection_id,
+                    duration = self.duration.as_deref(),
+                    "sing-box: {}",
+                    self.message
+                )
+            };
+        }
+
+        match self.level {
+            Some(LogLevel::Trace) => event!(trace),
+            Some(LogLevel::Debug) => event!(debug),
+            Some(LogLevel::Info) | None => event!(info),
+            Some(LogLevel::Warn) => event!(warn),
+            Some(LogLevel::Error | LogLevel::Fatal | LogLevel::Panic) => event!(error),
+        }
+    }
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(timestamp) = &self.timestamp {
+            write!(f, "{} ", timestamp)?;
+        }
+        if let Some(level) = &self.level {
+            write!(f, "{} ", level.as_str())?;
+        }
+        match (&self.connection_id, &self.duration) {
+            (Some(id), Some(duration)) => write!(f, "[{} {}] ", id, duration)?,
+            (Some(id), None) => write!(f, "[{}] ", id)?,
+            _ => {}
+        }
+        if let Some(component) = &self.component {
+            write!(f, "{}: ", component)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Ring buffer of the most recent sing-box log lines.
+#[derive(Debug)]
+pub struct LogBuffer {
+    lines: VecDeque<LogLine>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, line: LogLine) {
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    /// Returns up to `count` of the most recent lines, oldest first.
+    pub fn recent(&self, count: usize) -> Vec<LogLine> {
+        let skip = self.lines.len().saturating_sub(count);
+        self.lines.iter().skip(skip).cloned().collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ErrorLogOptions {
+    /// Distinct entries kept per reporting cycle, further ones are only
+    /// counted. Collection is disabled when 0.
+    pub max_entries: usize,
+
+    /// Replace user passwords in messages.
+    pub redact_passwords: bool,
+
+    /// Replace IP addresses in messages.
+    pub redact_ips: bool,
+}
+
+impl Default for ErrorLogOptions {
+    fn default() -> Self {
+        Self {
+            max_entries: 50,
+            redact_passwords: true,
+            redact_ips: true,
+        }
+    }
+}
+
+/// A WARN or worse line, with the number of times it was seen.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorLogEntry {
+    pub level: LogLevel,
+    pub component: Option<String>,
+    pub message: String,
+    pub count: u64,
+    /// Unix timestamps in seconds.
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// Body posted to the panel's logs endpoint.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorLogReport {
+    pub entries: Vec<ErrorLogEntry>,
+    /// Lines not kept because `max_entries` was reached.
+    pub dropped: u64,
+}
+
+impl ErrorLogReport {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.dropped == 0
+    }
+}
+
+/// Collects WARN and worse sing-box lines between two reports, redacted and
+/// deduplicated by level, component and message, with the ports of addresses
+/// dropped.
+#[derive(Debug)]
+pub struct ErrorLog {
+    options: ErrorLogOptions,
+    secrets: Vec<String>,
+    entries: Vec<ErrorLogEntry>,
+    index: HashMap<(LogLevel, Option<String>, String), usize>,
+    dropped: u64,
+}
+
+impl ErrorLog {
+    pub fn new(options: ErrorLogOptions) -> Self {
+        Self {
+            options,
+            secrets: Vec::new(),
+            entries: Vec::new(),
+            index: HashMap::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Sets the passwords redacted from messages.
+    pub fn set_secrets(&mut self, mut secrets: Vec<String>) {
+        secrets.retain(|secret| !secret.is_empty());
+        // Longest first, so a password containing another is fully replaced
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        self.secrets = secrets;
+    }
+
+    pub fn push(&mut self, line: &LogLine) {
+        let Some(level) = line.level.filter(|level| *level >= LogLevel::Warn) else {
+            return;
+        };
+        if self.options.max_entries == 0 {
+            return;
+        }
+
+        let message = self.redact(&strip_ports(&line.message));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        self.merge(ErrorLogEntry {
+            level,
+            component: line.component.clone(),
+            message,
+            count: 1,
+            first_seen: now,
+            last_seen: now,
+        });
+    }
+
+    /// Puts back a report that couldn't be posted, ahead of what was collected
+    /// since.
+    pub fn restore(&mut self, report: ErrorLogReport) {
+        let newer = self.take();
+        self.dropped = report.dropped + newer.dropped;
+        for entry in report.entries.into_iter().chain(newer.entries) {
+            self.merge(entry);
+        }
+    }
+
+    fn merge(&mut self, entry: ErrorLogEntry) {
+        let key = (entry.level, entry.component.clone(), entry.message.clone());
+        if let Some(&i) = self.index.get(&key) {
+            let existing = &mut self.entries[i];
+            existing.count += entry.count;
+            existing.first_seen = existing.first_seen.min(entry.first_seen);
+            existing.last_seen = existing.last_seen.max(entry.last_seen);
+            return;
+        }
+
+        if self.entries.len() >= self.options.max_entries {
+            self.dropped += entry.count;
+            return;
+        }
+
+        self.entries.push(entry);
+        self.index.insert(key, self.entries.len() - 1);
+    }
+
+    /// Returns everything collected since the last call.
+    pub fn take(&mut self) -> ErrorLogReport {
+        self.index.clear();
+        ErrorLogReport {
+            entries: std::mem::take(&mut self.entries),
+            dropped: std::mem::take(&mut self.dropped),
+        }
+    }
+
+    fn redact(&self, message: &str) -> String {
+        let mut message = message.to_string();
+
+        if self.options.redact_passwords {
+            for secret in &self.secrets {
+                message = message.replace(secret.as_str(), "<redacted>");
+            }
+        }
+
+        if self.options.redact_ips {
+            message = IP_REGEX
+                .replace_all(&message, |captures: &Captures| redact_ip(&captures[0]))
+                .into_owned();
+        }
+
+        message
+    }
+}
+
+/// Replaces `ip:port` pairs with the IP.
+fn strip_ports(message: &str) -> String {
+    ADDR_REGEX
+        .replace_all(message, |captures: &Captures| {
+            let ip = captures
+                .name("v6")
+                .or_else(|| captures.name("v4"))
+                .unwrap()
+                .as_str();
+            match ip.parse::<IpAddr>() {
+                Ok(_) => ip.to_string(),
+                Err(_) => captures[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Replaces `candidate` if it is an IP address, keeping the port of an
+/// `ip:port` pair and trailing punctuation.
+fn redact_ip(candidate: &str) -> String {
+    let address = candidate.trim_end_matches([':', '.']);
+    let suffix = &candidate[address.len()..];
+
+    if address.parse::<IpAddr>().is_ok() {
+        return format!("<ip>{}", suffix);
+    }
+
+    match address.rsplit_once(':') {
+        Some((ip, port)) if ip.parse::<IpAddr>().is_ok() && port.parse::<u16>().is_ok() => {
+            format!("<ip>:{}{}", port, suffix)
+        }
+        _ => candidate.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let line = LogLine::parse(
+            "+0800 2024-12-05 12:00:00 INFO [3735928559 12ms] inbound/shadowsocks[ss-in]: \
+             inbound connection from 1.2.3.4:5678",
+        );
+
+        assert_eq!(line.timestamp.as_deref(), Some("+0800 2024-12-05 12:00:00"));
+        assert_eq!(line.level, Some(LogLevel::Info));
+        assert_eq!(line.connection_id, Some(3735928559));
+        assert_eq!(line.duration.as_deref(), Some("12ms"));
+        assert_eq!(
+            line.component.as_deref(),
+            Some("inbound/shadowsocks[ss-in]")
+        );
+        assert_eq!(line.message, "inbound connection from 1.2.3.4:5678");
+
+        let line = LogLine::parse("WARN router: ERROR and INFO in the message");
+        assert_eq!(line.timestamp, None);
+        assert_eq!(line.level, Some(LogLevel::Warn));
+        assert_eq!(line.component.as_deref(), Some("router"));
+        assert_eq!(line.message, "ERROR and INFO in the message");
+        assert_eq!(
+            line.to_string(),
+            "WARN router: ERROR and INFO in the message"
+        );
+
+        let line = LogLine::parse("INFO sing-box started (0.12s)");
+        assert_eq!(line.component, None);
+        assert_eq!(line.message, "sing-box started (0.12s)");
+
+        // sing-box's default stderr output
+        let line = LogLine::parse(
+            "\x1b[36mINFO\x1b[0m[0012] \x1b[38;5;99m[3735928559 12ms]\x1b[0m \
+             inbound/shadowsocks[ss-in]: inbound connection from 1.2.3.4:5678",
+        );
+        assert_eq!(line.timestamp, None);
+        assert_eq!(line.level, Some(LogLevel::Info));
+        assert_eq!(line.connection_id, Some(3735928559));
+        assert_eq!(
+            line.component.as_deref(),
+            Some("inbound/shadowsocks[ss-in]")
+        );
+        assert_eq!(line.message, "inbound connection from 1.2.3.4:5678");
+
+        let line = LogLine::parse("\x1b[31mERROR\x1b[0m[0000] router: no route");
+        assert_eq!(line.level, Some(LogLevel::Error));
+        assert_eq!(line.to_string(), "ERROR router: no route");
+
+        // Xray's error log
+        let line = LogLine::parse(
+            "2024/12/05 12:00:00.123456 [Warning] [3735928559] proxy/shadowsocks: \
+             failed to read from 1.2.3.4:5678",
+        );
+        assert_eq!(
+            line.timestamp.as_deref(),
+            Some("2024/12/05 12:00:00.123456")
+        );
+        assert_eq!(line.level, Some(LogLevel::Warn));
+        assert_eq!(line.connection_id, Some(3735928559));
+        assert_eq!(line.component.as_deref(), Some("proxy/shadowsocks"));
+        assert_eq!(line.message, "failed to read from 1.2.3.4:5678");
+
+        let line = LogLine::parse("2024/12/05 12:00:00 [Error] core: failed to start");
+        assert_eq!(line.level, Some(LogLevel::Error));
+        assert_eq!(line.connection_id, None);
+        assert_eq!(line.component.as_deref(), Some("core"));
+
+        let line = LogLine::parse("panic: runtime error: INFO");
+        assert_eq!(line.level, None);
+        assert_eq!(line.message, "panic: runtime error: INFO");
+    }
+
+    #[test]
+    fn test_buffer() {
+        let mut buffer = LogBuffer::new(2);
+        for message in ["first", "second", "third"] {
+            buffer.push(LogLine::parse(message));
+        }
+
+        let messages: Vec<String> = buffer.recent(5).into_iter().map(|l| l.message).collect();
+        assert_eq!(messages, ["second", "third"]);
+        assert_eq!(buffer.recent(1)[0].message, "third");
+    }
+
+    #[test]
+    fn test_error_log() {
+        let mut log = ErrorLog::new(ErrorLogOptions {
+            max_entries: 2,
+            ..Default::default()
+        });
+        log.set_secrets(vec!["hunter2".to_string()]);
+
+        for line in [
+            "INFO inbound/shadowsocks[ss-in]: inbound connection from 1.2.3.4:5678",
+            "ERROR inbound/shadowsocks[ss-in]: process connection from 1.2.3.4:5678: bad key hunter2",
+            "ERROR inbound/shadowsocks[ss-in]: process connection from 5.6.7.8:9012: bad key hunter2",
+            "WARN dns: lookup [2001:db8::1]:53 timed out after 1.5s",
+            "ERROR router: something else",
+        ] {
+            log.push(&LogLine::parse(line));
+        }
+
+        let report = log.take();
+        assert_eq!(report.dropped, 1);
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(
+            report.entries[0].message,
+            "process connection from <ip>: bad key <redacted>"
+        );
+        assert_eq!(report.entries[0].count, 2);
+        assert_eq!(report.entries[1].level, LogLevel::Warn);
+        assert_eq!(
+            report.entries[1].message,
+            "lookup <ip> timed out after 1.5s"
+        );
+
+        assert!(log.take().is_empty());
+
+        // A report that failed to post is merged with what came after it
+        log.push(&LogLine::parse("ERROR router: something else"));
+        log.restore(report);
+        let report = log.take();
+        assert_eq!(report.dropped, 2);
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(report.entries[0].count, 2);
+    }
+
+    #[test]
+    fn test_error_log_ports() {
+        let mut log = ErrorLog::new(ErrorLogOptions {
+            redact_ips: false,
+            ..Default::default()
+        });
+
+        for line in [
+            "ERROR inbound/shadowsocks[ss-in]: process connection from 1.2.3.4:1111: EOF",
+            "ERROR inbound/shadowsocks[ss-in]: process connection from 1.2.3.4:2222: EOF",
+            "ERROR inbound/shadowsocks[ss-in]: process connection from [2001:db8::1]:3333: EOF",
+        ] {
+            log.push(&LogLine::parse(line));
+        }
+
+        let report = log.take();
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(
+            report.entries[0].message,
+            "process connection from 1.2.3.4: EOF"
+        );
+        assert_eq!(report.entries[0].count, 2);
+        assert_eq!(
+            report.entries[1].message,
+            "process connection from 2001:db8::1: EOF"
+        );
+    }
+}
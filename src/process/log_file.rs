@@ -0,0 +1,115 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub struct LogFileOptions {
+    pub path: PathBuf,
+    /// Size in bytes after which the file is rotated.
+    pub max_size: u64,
+    /// Number of rotated files kept next to the active one.
+    pub max_files: usize,
+}
+
+/// Size-based rotating log file. `sing-box.log` rolls over to
+/// `sing-box.log.1`, which rolls over to `sing-box.log.2` and so on.
+pub struct RotatingFile {
+    options: LogFileOptions,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(options: LogFileOptions) -> io::Result<Self> {
+        if let Some(parent) = options.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = open_append(&options.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            options,
+            file,
+            size,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+
+        if self.size > 0 && self.size + len > self.options.max_size {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.options.path;
+
+        if self.options.max_files == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for i in (1..self.options.max_files).rev() {
+                let from = rotated_path(path, i);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(path, i + 1))?;
+                }
+            }
+            fs::rename(path, rotated_path(path, 1))?;
+        }
+
+        self.file = open_append(path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_rotation() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.child("sing-box.log");
+
+        let mut file = RotatingFile::open(LogFileOptions {
+            path: path.clone(),
+            max_size: 10,
+            max_files: 2,
+        })
+        .unwrap();
+
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "second\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
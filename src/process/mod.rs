@@ -1,43 +1,321 @@
+use error::ProcessError;
+use event::ProcessEvent;
+use limits::ResourceLimits;
+use log::{ErrorLog, ErrorLogOptions, ErrorLogReport, LogBuffer, LogLine};
+use log_file::{LogFileOptions, RotatingFile};
+use pid_file::PidFile;
+use privileges::Credentials;
 use std::io;
-use std::path::PathBuf;
-use std::process::Stdio;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
 use tokio::process::Command;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, timeout};
 use tracing::{debug, error, info, warn};
 
+use crate::api::v2ray_api::StatsFormatResponse;
+use crate::core::{Core, CoreKind};
+
+pub mod blue_green;
+pub mod error;
+pub mod event;
+pub mod install;
+pub mod limits;
+pub mod log;
+pub mod log_file;
+pub mod pid_file;
+pub mod privileges;
+pub mod reload;
+pub mod version;
+
+#[derive(Clone, Debug)]
+pub struct ProcessOptions {
+    /// Proxy core the process runs.
+    pub core: Arc<dyn Core>,
+
+    /// Core binary, its `binary_name` in the current directory or on PATH
+    /// when unset.
+    pub binary: Option<PathBuf>,
+
+    /// Extra arguments appended to `run -c <config>`.
+    pub args: Vec<String>,
+
+    /// Working directory passed to sing-box with `-D`.
+    pub working_dir: Option<PathBuf>,
+
+    /// Environment variables set on the child.
+    pub env: Vec<(String, String)>,
+
+    /// Re-emit sing-box output through tracing.
+    pub logout: bool,
+
+    /// Write sing-box output to a rotating file managed by the pod.
+    pub log_file: Option<LogFileOptions>,
+
+    /// How long `stop` waits for sing-box to exit before killing it.
+    pub stop_timeout: Duration,
+
+    /// How long `start` waits for sing-box to listen on its configured ports.
+    pub ready_timeout: Duration,
+
+    /// Collection of WARN and ERROR lines reported to the panel.
+    pub error_log: ErrorLogOptions,
+
+    /// rlimits, niceness and cgroup applied to the child.
+    pub limits: ResourceLimits,
+
+    /// User and group sing-box runs as, the pod's when unset.
+    pub credentials: Option<Credentials>,
+
+    /// Adopt a sing-box left running by a previous pod instance instead of
+    /// terminating it.
+    pub adopt: bool,
+
+    /// FIFO sing-box logs to instead of stderr, see `ConfigOptions::log_pipe`.
+    /// Unlike stderr it outlives the pod, so an adopted sing-box keeps logging.
+    pub log_pipe: Option<PathBuf>,
+
+    /// Restart sing-box when it exits without being stopped, see `supervise`.
+    pub auto_restart: bool,
+
+    /// Upper bound of the exponential backoff between restart attempts.
+    pub restart_max_delay: Duration,
+
+    /// How long the previous sing-box keeps serving its connections after a
+    /// blue/green restart. Binary upgrades and structural config changes
+    /// restart sing-box this way when set, see `blue_green_restart`.
+    pub drain_period: Option<Duration>,
+}
+
+/// Delay before the first restart attempt, doubled on every further attempt.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// A process that ran this long before exiting restarts the backoff.
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(60);
+
+/// Number of sing-box log lines kept for diagnostics.
+const RECENT_LOG_LINES: usize = 200;
+
+/// Number of log lines included in a failed start's error.
+const STDERR_TAIL_LINES: usize = 20;
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            core: CoreKind::default().core(),
+            binary: None,
+            args: Vec::new(),
+            working_dir: None,
+            env: Vec::new(),
+            logout: false,
+            log_file: None,
+            stop_timeout: Duration::from_secs(10),
+            ready_timeout: Duration::from_secs(30),
+            error_log: ErrorLogOptions::default(),
+            limits: ResourceLimits::default(),
+            credentials: None,
+            adopt: false,
+            log_pipe: None,
+            auto_restart: true,
+            restart_max_delay: Duration::from_secs(60),
+            drain_period: None,
+        }
+    }
+}
+
+impl ProcessOptions {
+    /// Resolves the sing-box binary and checks that it can be executed.
+    pub fn resolve_binary(&self) -> Result<PathBuf, ProcessError> {
+        resolve_binary(self.binary.clone(), self.core.as_ref())
+    }
+}
+
 #[derive(Clone)]
 pub struct ProcessManager {
     pid: Arc<Mutex<Option<u32>>>,
+    /// Current binary, starts as `options.binary` and changes on upgrades.
+    binary: Arc<parking_lot::Mutex<Option<PathBuf>>>,
+    /// Exit of the current process, published by its watcher task.
+    exited: Arc<Mutex<Option<watch::Receiver<Option<Exited>>>>>,
+    /// Whether the current process was adopted from a previous pod instance.
+    adopted: Arc<AtomicBool>,
+    /// Set while the process is stopped on request, so its exit isn't
+    /// mistaken for a crash.
+    stopping: Arc<AtomicBool>,
+    /// Number of `stop` calls, a pending restart is abandoned when it changes.
+    stop_requests: Arc<AtomicU64>,
+    events: broadcast::Sender<ProcessEvent>,
+    /// Last lines sing-box wrote to stderr.
+    recent_logs: Arc<parking_lot::Mutex<LogBuffer>>,
+    /// WARN and ERROR lines not yet reported to the panel.
+    error_log: Arc<parking_lot::Mutex<ErrorLog>>,
+    /// Task reading `options.log_pipe`.
+    log_pipe_task: Arc<parking_lot::Mutex<Option<JoinHandle<()>>>>,
+    /// Config the current process runs, `config_path` or a copy made by a
+    /// blue/green restart.
+    active_config: Arc<parking_lot::Mutex<PathBuf>>,
+    /// Held for the whole of a blue/green restart, including the drain.
+    blue_green: Arc<Mutex<()>>,
+    /// Whether a restart from `spawn_blue_green_restart` waits for the
+    /// running one.
+    blue_green_queued: Arc<AtomicBool>,
+    /// Previous process of a blue/green restart while it drains.
+    draining: Arc<parking_lot::Mutex<Option<blue_green::Draining>>>,
+    /// Traffic of drained processes and failed reports, not yet posted to
+    /// the panel.
+    unreported_stats: Arc<parking_lot::Mutex<Option<StatsFormatResponse>>>,
     config_path: PathBuf,
-    logout: Option<bool>,
+    options: ProcessOptions,
 }
 
 impl ProcessManager {
-    pub fn new(config_path: PathBuf, logout: Option<bool>) -> Self {
+    pub fn new(config_path: PathBuf, options: ProcessOptions) -> Self {
         Self {
             pid: Arc::new(Mutex::new(None)),
+            binary: Arc::new(parking_lot::Mutex::new(options.binary.clone())),
+            exited: Arc::new(Mutex::new(None)),
+            adopted: Arc::new(AtomicBool::new(false)),
+            stopping: Arc::new(AtomicBool::new(false)),
+            stop_requests: Arc::new(AtomicU64::new(0)),
+            events: broadcast::channel(32).0,
+            recent_logs: Arc::new(parking_lot::Mutex::new(LogBuffer::new(RECENT_LOG_LINES))),
+            error_log: Arc::new(parking_lot::Mutex::new(ErrorLog::new(
+                options.error_log.clone(),
+            ))),
+            log_pipe_task: Arc::new(parking_lot::Mutex::new(None)),
+            active_config: Arc::new(parking_lot::Mutex::new(config_path.clone())),
+            blue_green: Arc::new(Mutex::new(())),
+            blue_green_queued: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(parking_lot::Mutex::new(None)),
+            unreported_stats: Arc::new(parking_lot::Mutex::new(None)),
             config_path,
-            logout,
+            options,
         }
     }
 
-    /// Starts the sing-box process.
-    pub async fn start(&self) -> io::Result<()> {
-        let current_dir_singbox = std::env::current_dir()?.join("sing-box");
+    /// Resolves the sing-box binary and checks that it can be executed.
+    pub fn binary(&self) -> Result<PathBuf, ProcessError> {
+        resolve_binary(self.binary.lock().clone(), self.options.core.as_ref())
+    }
+
+    /// Switches to another sing-box binary and restarts the process with it.
+    pub async fn restart_with(&self, binary: PathBuf) -> Result<(), ProcessError> {
+        check_executable(&binary)?;
 
-        let mut command = if current_dir_singbox.exists() {
-            Command::new(current_dir_singbox)
-        } else {
-            Command::new("sing-box")
+        info!("Restarting sing-box with {}", binary.display());
+        let previous = self.binary.lock().replace(binary);
+
+        if self.blue_green_enabled() && self.is_running().await {
+            let result = self.blue_green_restart().await;
+            // The previous binary keeps running when the new one fails
+            if result.is_err() {
+                *self.binary.lock() = previous;
+            }
+            return result;
+        }
+
+        self.stop().await?;
+        self.start().await
+    }
+
+    /// Starts the sing-box process and waits until it is ready, see `wait_ready`.
+    ///
+    /// A sing-box left running by a previous pod instance is adopted when
+    /// `adopt` is set and it runs the same config, and terminated otherwise
+    /// so that it doesn't hold on to the ports.
+    pub async fn start(&self) -> Result<(), ProcessError> {
+        self.stopping.store(false, Ordering::Relaxed);
+
+        let pid_file = self.pid_file_path();
+        if let Some(orphan) = PidFile::read(&pid_file).filter(PidFile::is_alive) {
+            if self.options.adopt && self.is_instance_config(&orphan.config) {
+                return self.adopt(orphan).await;
+            }
+            terminate_orphan(&orphan, self.options.stop_timeout).await?;
+            PidFile::remove(&pid_file);
+        }
+
+        self.adopted.store(false, Ordering::Relaxed);
+        self.reset_active_config();
+        self.emit(ProcessEvent::Starting);
+
+        let mut instance = self.spawn(&self.config_path).await?;
+
+        if let Err(e) = self
+            .wait_ready(
+                &self.config_path,
+                &instance.exited,
+                instance.stderr_task.as_mut(),
+                None,
+            )
+            .await
+        {
+            // Don't leave a half-started process behind
+            let _ = self.stop_process().await;
+            return Err(e);
+        }
+
+        if let Some(pid) = instance.pid {
+            self.emit(ProcessEvent::Started { pid });
+        }
+
+        Ok(())
+    }
+
+    /// Spawns sing-box with `config` and makes it the current process.
+    async fn spawn(&self, config: &Path) -> Result<Instance, ProcessError> {
+        let pid_file = self.pid_file_path();
+        let binary = self.binary()?;
+        let mut command = Command::new(&binary);
+
+        self.options
+            .core
+            .run_command(&mut command, config, self.options.working_dir.as_deref());
+        self.options.limits.apply(&mut command)?;
+        // After the limits, lowering them may need privileges
+        if let Some(credentials) = &self.options.credentials {
+            let uses_tun = read_config(config).is_some_and(|c| self.options.core.uses_tun(&c));
+            credentials.apply(&mut command, uses_tun)?;
+        }
+
+        // Keep terminal signals meant for the pod away from a sing-box that outlives it
+        #[cfg(unix)]
+        if self.options.adopt {
+            command.process_group(0);
+        }
+
+        let sink = self.log_sink()?;
+        // Must be open before sing-box opens it for writing, or sing-box blocks
+        self.open_log_pipe(&sink)?;
+
+        // With a log pipe, sing-box writes nothing to stdout and may outlive
+        // the pod. Discard stdout and send stderr to the log pipe, opened
+        // read-write so that sing-box always holds a reader and isn't killed
+        // by SIGPIPE once the pod is gone.
+        let (stdout, stderr) = match &self.options.log_pipe {
+            Some(path) => {
+                let pipe = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)?;
+                (Stdio::null(), Stdio::from(pipe))
+            }
+            None => (Stdio::piped(), Stdio::piped()),
         };
 
         let mut child = command
-            .args(&["run", "-c", self.config_path.to_str().unwrap()])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+            .args(&self.options.args)
+            .envs(self.options.env.iter().map(|(k, v)| (k, v)))
+            .stdout(stdout)
+            .stderr(stderr)
+            .spawn()
+            .map_err(|source| ProcessError::Spawn { binary, source })?;
 
         info!("sing-box process started");
 
@@ -47,142 +325,524 @@ impl ProcessManager {
             *pid_guard = pid;
         }
 
-        let stdout = child.stdout.take().expect("Failed to take stdout");
-        let stderr = child.stderr.take().expect("Failed to take stderr");
+        if let Some(pid) = pid
+            && let Err(e) = PidFile::new(pid, config.to_path_buf()).write(&pid_file)
+        {
+            warn!("Failed to write pid file {}: {}", pid_file.display(), e);
+        }
 
-        // Copy logout value for logging tasks
-        let logout = self.logout;
-        let _stdout_task = tokio::spawn(async move {
-            let mut stdout_reader = BufReader::new(stdout).lines();
-            while let Ok(Some(line)) = stdout_reader.next_line().await {
-                if let Some(true) = logout {
-                    info!("sing-box STDOUT: {}", line);
+        // Xray logs to stdout unless it has a log file, parse it like stderr
+        if let Some(stdout) = child.stdout.take() {
+            let stdout_sink = sink.clone();
+            let _stdout_task = tokio::spawn(async move {
+                let mut stdout_reader = BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = stdout_reader.next_line().await {
+                    stdout_sink.write(&line);
                 }
-            }
-            debug!("stdout_task finished reading");
-        });
+                debug!("stdout_task finished reading");
+            });
+        }
 
-        let logout = self.logout;
-        let _stderr_task = tokio::spawn(async move {
-            let mut stderr_reader = BufReader::new(stderr).lines();
-            while let Ok(Some(line)) = stderr_reader.next_line().await {
-                if let Some(true) = logout {
-                    // Simple string checks to categorize logs
-                    if line.contains("INFO") {
-                        info!("sing-box: {}", line);
-                    } else if line.contains("WARN") {
-                        warn!("sing-box: {}", line);
-                    } else if line.contains("ERROR") {
-                        error!("sing-box: {}", line);
-                    } else if line.contains("DEBUG") {
-                        debug!("sing-box: {}", line);
-                    } else {
-                        info!("sing-box: {}", line);
-                    }
+        // The log pipe task reads stderr otherwise
+        let stderr_task = child.stderr.take().map(|stderr| {
+            tokio::spawn(async move {
+                let mut stderr_reader = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = stderr_reader.next_line().await {
+                    sink.write(&line);
                 }
-            }
-            debug!("stderr_task finished reading");
+                debug!("stderr_task finished reading");
+            })
         });
 
         // -------------------------------------------------------------------------
         // Background task that periodically checks if the child is still alive
         // without calling .take() or .wait().
         // -------------------------------------------------------------------------
+        let (exit_tx, exit_rx) = watch::channel(None);
+        *self.exited.lock().await = Some(exit_rx.clone());
+
         let child_arc = Arc::new(Mutex::new(Some(child)));
         let pid_ref = self.pid.clone();
+        let events = self.events.clone();
         tokio::spawn(async move {
             // hold the unique ownership of child
             let mut guard = child_arc.lock().await;
             if let Some(mut ch) = guard.take() {
-                match ch.wait().await {
-                    Ok(status) => info!("sing-box process exited with status: {}", status),
-                    Err(e) => error!("Failed to wait on sing-box: {}", e),
+                let status = ch.wait().await;
+                // process has exited, clean up the PID unless a blue/green
+                // restart already replaced it
+                {
+                    let mut pid_guard = pid_ref.lock().await;
+                    if *pid_guard == pid {
+                        *pid_guard = None;
+                    }
                 }
-                // process has exited, clean up the PID
+                remove_pid_file(&pid_file, pid);
+                let status = match status {
+                    Ok(status) => {
+                        info!("sing-box process exited with status: {}", status);
+                        Some(status)
+                    }
+                    Err(e) => {
+                        error!("Failed to wait on sing-box: {}", e);
+                        None
+                    }
+                };
+                let _ = exit_tx.send(Some(Exited(status)));
+                let _ = events.send(ProcessEvent::Exited { status });
+            }
+        });
+
+        Ok(Instance {
+            pid,
+            exited: exit_rx,
+            stderr_task,
+        })
+    }
+
+    /// Takes over a sing-box started by a previous pod instance. It isn't our
+    /// child, so its exit is detected by polling and its status is unknown.
+    async fn adopt(&self, orphan: PidFile) -> Result<(), ProcessError> {
+        info!(
+            "Adopting sing-box (pid={}) left running by a previous pod instance",
+            orphan.pid
+        );
+
+        self.adopted.store(true, Ordering::Relaxed);
+        *self.pid.lock().await = Some(orphan.pid);
+        *self.active_config.lock() = orphan.config.clone();
+
+        // Reattach to its logs
+        let sink = self.log_sink()?;
+        self.open_log_pipe(&sink)?;
+
+        let (exit_tx, exit_rx) = watch::channel(None);
+        *self.exited.lock().await = Some(exit_rx.clone());
+
+        let pid = orphan.pid;
+        let config = orphan.config.clone();
+        let pid_ref = self.pid.clone();
+        let pid_file = self.pid_file_path();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            while orphan.is_alive() {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+            {
                 let mut pid_guard = pid_ref.lock().await;
-                *pid_guard = None;
+                if *pid_guard == Some(pid) {
+                    *pid_guard = None;
+                }
             }
+            remove_pid_file(&pid_file, Some(pid));
+            info!("Adopted sing-box process exited");
+            let _ = exit_tx.send(Some(Exited(None)));
+            let _ = events.send(ProcessEvent::Exited { status: None });
         });
 
+        self.wait_ready(&config, &exit_rx, None, None).await?;
+        self.emit(ProcessEvent::Started { pid });
+
         Ok(())
     }
 
-    /// Stops the sing-box process.
-    pub async fn stop(&self) -> io::Result<()> {
-        let pid = *self.pid.lock().await;
-        if let Some(pid) = pid {
-            info!("Stopping sing-box process (pid={}) ...", pid);
+    /// Subscribes to lifecycle events of the sing-box process.
+    pub fn subscribe(&self) -> broadcast::Receiver<ProcessEvent> {
+        self.events.subscribe()
+    }
 
-            #[cfg(unix)]
-            {
-                use nix::sys::signal::{Signal, kill};
-                use nix::unistd::Pid;
-                let _ = kill(Pid::from_raw(pid as i32), Signal::SIGTERM);
+    fn emit(&self, event: ProcessEvent) {
+        debug!("sing-box process event: {:?}", event);
+        // No subscribers is fine
+        let _ = self.events.send(event);
+    }
+
+    /// Restarts sing-box with exponential backoff whenever it exits without
+    /// being stopped, until it is stopped. Does nothing unless `auto_restart`
+    /// is set.
+    pub fn supervise(&self) -> JoinHandle<()> {
+        let manager = self.clone();
+        let mut events = self.subscribe();
+
+        tokio::spawn(async move {
+            if !manager.options.auto_restart {
+                return;
             }
 
-            #[cfg(windows)]
-            {
-                // use Windows native API TerminateProcess
-                use winapi::um::handleapi::CloseHandle;
-                use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
-                use winapi::um::winnt::PROCESS_TERMINATE;
-
-                unsafe {
-                    let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
-                    if handle.is_null() {
-                        error!("OpenProcess failed (PID={}), maybe it's already gone.", pid);
-                    } else {
-                        if TerminateProcess(handle, 1) == 0 {
-                            error!(
-                                "TerminateProcess failed, last_error={}",
-                                std::io::Error::last_os_error()
-                            );
-                        } else {
-                            info!("TerminateProcess success for PID={}", pid);
+            let mut attempt = 0;
+            let mut started_at = Instant::now();
+
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                match event {
+                    ProcessEvent::Started { .. } => started_at = Instant::now(),
+                    // Exits of failed restart attempts are stale once one succeeded
+                    ProcessEvent::Exited { .. }
+                        if !manager.stopping.load(Ordering::Relaxed)
+                            && !manager.is_running().await =>
+                    {
+                        if started_at.elapsed() >= RESTART_RESET_AFTER {
+                            attempt = 0;
                         }
-                        CloseHandle(handle);
+                        manager.restart(&mut attempt).await;
                     }
+                    _ => {}
                 }
             }
-        } else {
-            info!("stop() called, but no sing-box process is running");
+        })
+    }
+
+    /// Restarts after an unexpected exit, retrying until a start succeeds or
+    /// the process is stopped.
+    async fn restart(&self, attempt: &mut u32) {
+        loop {
+            let stop_requests = self.stop_requests.load(Ordering::Relaxed);
+            *attempt += 1;
+            let delay = RESTART_DELAY
+                .saturating_mul(2u32.saturating_pow(*attempt - 1))
+                .min(self.options.restart_max_delay);
+
+            warn!(
+                "sing-box exited unexpectedly, restarting in {}s (attempt {})",
+                delay.as_secs(),
+                attempt
+            );
+            self.emit(ProcessEvent::Restarting { attempt: *attempt });
+            tokio::time::sleep(delay).await;
+
+            if self.stop_requests.load(Ordering::Relaxed) != stop_requests {
+                return;
+            }
+
+            match self.start().await {
+                Ok(()) => return,
+                Err(e) => error!("Error restarting sing-box: {}", e),
+            }
+        }
+    }
+
+    /// Whether the running sing-box was adopted rather than started by this
+    /// pod instance, in which case it may still run an older config.
+    pub fn is_adopted(&self) -> bool {
+        self.adopted.load(Ordering::Relaxed)
+    }
+
+    fn pid_file_path(&self) -> PathBuf {
+        PidFile::path(&self.config_path)
+    }
+
+    /// Where sing-box output goes, the log file is reopened on every start.
+    fn log_sink(&self) -> io::Result<LogSink> {
+        let log_file = match &self.options.log_file {
+            Some(options) => Some(Arc::new(parking_lot::Mutex::new(RotatingFile::open(
+                options.clone(),
+            )?))),
+            None => None,
+        };
+
+        self.recent_logs.lock().clear();
+
+        Ok(LogSink {
+            log_file,
+            logout: self.options.logout,
+            recent_logs: self.recent_logs.clone(),
+            error_log: self.error_log.clone(),
+        })
+    }
+
+    /// Creates the log pipe if needed and (re)starts reading it.
+    #[cfg(unix)]
+    fn open_log_pipe(&self, sink: &LogSink) -> io::Result<()> {
+        use nix::sys::stat::Mode;
+        use tokio::net::unix::pipe;
+
+        let Some(path) = &self.options.log_pipe else {
+            return Ok(());
+        };
+
+        if !path.exists() {
+            nix::unistd::mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR)?;
+            if let Some(credentials) = &self.options.credentials {
+                credentials.chown(path)?;
+            }
+        }
+
+        // Opened read-write so that the reader never sees EOF while sing-box
+        // reopens the pipe on reload
+        let receiver = pipe::OpenOptions::new()
+            .read_write(true)
+            .open_receiver(path)?;
+
+        let sink = sink.clone();
+        let task = tokio::spawn(async move {
+            let mut lines = BufReader::new(receiver).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                sink.write(&line);
+            }
+            debug!("log pipe task finished reading");
+        });
+
+        if let Some(previous) = self.log_pipe_task.lock().replace(task) {
+            previous.abort();
         }
 
         Ok(())
     }
 
+    #[cfg(windows)]
+    fn open_log_pipe(&self, _sink: &LogSink) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Waits until sing-box accepts connections on every TCP inbound and the
+    /// v2ray API endpoint of `config`. Fails with the stderr tail if it exits
+    /// first or isn't ready within `ready_timeout`.
+    ///
+    /// With `owner` set, the ports must be listened on by that process, as a
+    /// connection to a shared port may be accepted by the previous sing-box.
+    async fn wait_ready(
+        &self,
+        config: &Path,
+        exited: &watch::Receiver<Option<Exited>>,
+        stderr_task: Option<&mut JoinHandle<()>>,
+        owner: Option<u32>,
+    ) -> Result<(), ProcessError> {
+        let mut pending = match tokio::fs::read_to_string(config).await {
+            Ok(content) => serde_json::from_str(&content)
+                .map(|config| self.options.core.probe_addrs(&config))
+                .unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to read sing-box config for readiness probe: {}", e);
+                Vec::new()
+            }
+        };
+        let deadline = Instant::now() + self.options.ready_timeout;
+
+        loop {
+            let exit = *exited.borrow();
+            if let Some(Exited(status)) = exit {
+                // Let the stderr task drain what sing-box wrote before exiting
+                if let Some(stderr_task) = stderr_task {
+                    let _ = timeout(Duration::from_secs(1), stderr_task).await;
+                }
+                return Err(ProcessError::ExitedDuringStartup {
+                    core: self.options.core.kind(),
+                    status,
+                    stderr: self.stderr_tail(),
+                });
+            }
+
+            let listening = owner.and_then(blue_green::listening_ports);
+            let mut not_ready = Vec::new();
+            for addr in pending {
+                let ready = match &listening {
+                    Some(ports) => blue_green::addr_port(&addr).is_some_and(|p| ports.contains(&p)),
+                    None => timeout(Duration::from_millis(500), TcpStream::connect(&addr))
+                        .await
+                        .is_ok_and(|result| result.is_ok()),
+                };
+                if !ready {
+                    not_ready.push(addr);
+                }
+            }
+            pending = not_ready;
+
+            if pending.is_empty() {
+                info!("sing-box is ready");
+                return Ok(());
+            }
+
+            if Instant::now() >= deadline {
+                return Err(ProcessError::NotReady {
+                    core: self.options.core.kind(),
+                    pending,
+                    timeout: self.options.ready_timeout,
+                    stderr: self.stderr_tail(),
+                });
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Last log lines, included in startup errors.
+    fn stderr_tail(&self) -> Vec<String> {
+        self.recent_logs(STDERR_TAIL_LINES)
+            .iter()
+            .map(LogLine::to_string)
+            .collect()
+    }
+
+    /// Sets the passwords redacted from reported log lines.
+    pub fn set_log_secrets(&self, secrets: Vec<String>) {
+        self.error_log.lock().set_secrets(secrets);
+    }
+
+    /// Returns the WARN and ERROR lines collected since the last call.
+    pub fn take_error_logs(&self) -> ErrorLogReport {
+        self.error_log.lock().take()
+    }
+
+    /// Traffic no longer counted by the running sing-box that still has to be
+    /// reported.
+    pub fn take_unreported_stats(&self) -> Option<StatsFormatResponse> {
+        self.unreported_stats.lock().take()
+    }
+
+    /// Keeps traffic that couldn't be posted for the next report.
+    pub fn restore_stats(&self, stats: StatsFormatResponse) {
+        self.unreported_stats
+            .lock()
+            .get_or_insert_default()
+            .merge(stats);
+    }
+
+    /// Puts back a report from `take_error_logs` that couldn't be posted.
+    pub fn restore_error_logs(&self, report: ErrorLogReport) {
+        self.error_log.lock().restore(report);
+    }
+
+    /// Returns up to `count` of the most recent sing-box log lines, oldest first.
+    pub fn recent_logs(&self, count: usize) -> Vec<LogLine> {
+        self.recent_logs.lock().recent(count)
+    }
+
+    /// Stops the sing-box process, waiting up to `stop_timeout` for it to exit
+    /// before killing it. Returns the exit status, `None` if nothing was running.
+    pub async fn stop(&self) -> Result<Option<ExitStatus>, ProcessError> {
+        self.stop_requests.fetch_add(1, Ordering::Relaxed);
+        self.stop_process().await
+    }
+
+    async fn stop_process(&self) -> Result<Option<ExitStatus>, ProcessError> {
+        self.stopping.store(true, Ordering::Relaxed);
+
+        let pid = *self.pid.lock().await;
+        let (Some(pid), Some(exited)) = (pid, self.exited.lock().await.clone()) else {
+            info!("stop() called, but no sing-box process is running");
+            return Ok(None);
+        };
+
+        info!("Stopping sing-box process (pid={}) ...", pid);
+
+        let status = self.terminate(pid, exited).await?;
+        self.emit(ProcessEvent::Stopped);
+
+        Ok(status)
+    }
+
+    async fn terminate(
+        &self,
+        pid: u32,
+        mut exited: watch::Receiver<Option<Exited>>,
+    ) -> Result<Option<ExitStatus>, ProcessError> {
+        #[cfg(unix)]
+        {
+            use nix::sys::signal::{Signal, kill};
+            use nix::unistd::Pid;
+            let _ = kill(Pid::from_raw(pid as i32), Signal::SIGTERM);
+
+            if let Ok(status) = timeout(self.options.stop_timeout, wait_exit(&mut exited)).await {
+                return Ok(status);
+            }
+
+            warn!(
+                "sing-box did not exit within {}s, sending SIGKILL",
+                self.options.stop_timeout.as_secs()
+            );
+            let _ = kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+        }
+
+        #[cfg(windows)]
+        {
+            // use Windows native API TerminateProcess
+            use winapi::um::handleapi::CloseHandle;
+            use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+            use winapi::um::winnt::PROCESS_TERMINATE;
+
+            unsafe {
+                let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+                if handle.is_null() {
+                    error!("OpenProcess failed (PID={}), maybe it's already gone.", pid);
+                } else {
+                    if TerminateProcess(handle, 1) == 0 {
+                        error!(
+                            "TerminateProcess failed, last_error={}",
+                            std::io::Error::last_os_error()
+                        );
+                    } else {
+                        info!("TerminateProcess success for PID={}", pid);
+                    }
+                    CloseHandle(handle);
+                }
+            }
+        }
+
+        timeout(self.options.stop_timeout, wait_exit(&mut exited))
+            .await
+            .map_err(|_| ProcessError::StopTimeout {
+                core: self.options.core.kind(),
+                pid,
+            })
+    }
+
     /// Reloads sing-box by sending a SIGHUP signal on Unix systems.
     /// For non-Unix, it stops and restarts the process.
     #[cfg(unix)]
-    pub async fn reload(&self) -> io::Result<()> {
+    pub async fn reload(&self) -> Result<(), ProcessError> {
+        if !self.options.core.reloads_on_sighup() {
+            return self.reload_by_restart().await;
+        }
+
         let pid = *self.pid.lock().await;
         if let Some(pid) = pid {
+            self.refresh_active_config()?;
             {
                 use nix::sys::signal::{Signal, kill};
                 use nix::unistd::Pid;
                 if let Err(e) = kill(Pid::from_raw(pid as i32), Signal::SIGHUP) {
                     error!("Failed to send SIGHUP: {}", e);
-                    return Err(io::Error::new(io::ErrorKind::Other, e));
+                    return Err(ProcessError::Signal {
+                        core: self.options.core.kind(),
+                        signal: "SIGHUP",
+                        source: e.into(),
+                    });
                 }
                 info!("Sent reload signal (SIGHUP) to sing-box");
+                self.emit(ProcessEvent::Reloaded);
                 Ok(())
             }
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "No running sing-box process found",
-            ))
+            Err(ProcessError::NotRunning {
+                core: self.options.core.kind(),
+            })
         }
     }
     /// Reloads sing-box by sending a SIGHUP signal on Unix systems.
     /// For WIndows, not SIGHUP, use stop + start
     #[cfg(windows)]
-    pub async fn reload(&self) -> io::Result<()> {
+    pub async fn reload(&self) -> Result<(), ProcessError> {
         info!("Reload on Windows -> stop + start");
+        self.reload_by_restart().await
+    }
+
+    /// Applies a new config to a core that can't reload it in place.
+    async fn reload_by_restart(&self) -> Result<(), ProcessError> {
+        // stop() returns once the previous process has exited and freed its ports
         self.stop().await?;
-        // Add a small delay to ensure the previous process is fully stopped
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-        self.start().await
+        self.start().await?;
+        self.emit(ProcessEvent::Reloaded);
+        Ok(())
+    }
+
+    /// Pid of the current process, `None` if nothing is running.
+    pub async fn pid(&self) -> Option<u32> {
+        *self.pid.lock().await
     }
 
     pub async fn is_running(&self) -> bool {
@@ -190,3 +850,244 @@ impl ProcessManager {
         pid.is_some()
     }
 }
+
+/// A spawned sing-box, see `ProcessManager::spawn`.
+struct Instance {
+    pid: Option<u32>,
+    exited: watch::Receiver<Option<Exited>>,
+    stderr_task: Option<JoinHandle<()>>,
+}
+
+/// Removes the pid file if it still belongs to `pid`.
+fn remove_pid_file(path: &Path, pid: Option<u32>) {
+    if PidFile::read(path).is_some_and(|pid_file| Some(pid_file.pid) == pid) {
+        PidFile::remove(path);
+    }
+}
+
+fn resolve_binary(binary: Option<PathBuf>, core: &dyn Core) -> Result<PathBuf, ProcessError> {
+    let binary = match binary {
+        Some(binary) => binary,
+        None => find_binary(core.binary_name()).ok_or_else(|| ProcessError::BinaryNotFound {
+            core: core.kind(),
+            flag: match core.kind() {
+                CoreKind::SingBox => "--singbox-bin",
+                CoreKind::Xray => "--xray-bin",
+            },
+        })?,
+    };
+
+    check_executable(&binary)?;
+
+    Ok(binary)
+}
+
+/// Looks for `name` in the current directory, then on PATH.
+fn find_binary(name: &str) -> Option<PathBuf> {
+    let current_dir = std::env::current_dir().ok().map(|dir| dir.join(name));
+    let path_dirs = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    current_dir
+        .into_iter()
+        .chain(path_dirs.into_iter().map(|dir| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+fn check_executable(binary: &Path) -> Result<(), ProcessError> {
+    let not_executable = |source| ProcessError::BinaryNotExecutable {
+        binary: binary.to_path_buf(),
+        source,
+    };
+    let metadata = std::fs::metadata(binary).map_err(not_executable)?;
+
+    if !metadata.is_file() {
+        return Err(not_executable(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a file",
+        )));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(not_executable(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "no execute permission",
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn read_config(config_path: &Path) -> Option<serde_json::Value> {
+    let content = std::fs::read_to_string(config_path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Published once sing-box is gone, with its status unless it could not be
+/// collected, as for an adopted process that isn't our child.
+#[derive(Clone, Copy, Debug)]
+struct Exited(Option<ExitStatus>);
+
+/// Waits for the watcher task to publish the exit status, `None` if it could
+/// not be collected.
+async fn wait_exit(exited: &mut watch::Receiver<Option<Exited>>) -> Option<ExitStatus> {
+    exited
+        .wait_for(Option::is_some)
+        .await
+        .ok()
+        .and_then(|exit| exit.and_then(|Exited(status)| status))
+}
+
+/// Terminates a sing-box left running by a previous pod instance.
+async fn terminate_orphan(orphan: &PidFile, stop_timeout: Duration) -> io::Result<()> {
+    warn!(
+        "Terminating sing-box (pid={}) left running by a previous pod instance",
+        orphan.pid
+    );
+
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{Signal, kill};
+        use nix::unistd::Pid;
+        let _ = kill(Pid::from_raw(orphan.pid as i32), Signal::SIGTERM);
+
+        if timeout(stop_timeout, wait_gone(orphan)).await.is_ok() {
+            return Ok(());
+        }
+
+        let _ = kill(Pid::from_raw(orphan.pid as i32), Signal::SIGKILL);
+    }
+
+    timeout(stop_timeout, wait_gone(orphan)).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("orphaned sing-box (pid={}) did not exit", orphan.pid),
+        )
+    })
+}
+
+async fn wait_gone(orphan: &PidFile) {
+    while orphan.is_alive() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Where sing-box output goes, shared by the tasks reading it.
+#[derive(Clone)]
+struct LogSink {
+    log_file: Option<Arc<parking_lot::Mutex<RotatingFile>>>,
+    logout: bool,
+    recent_logs: Arc<parking_lot::Mutex<LogBuffer>>,
+    error_log: Arc<parking_lot::Mutex<ErrorLog>>,
+}
+
+impl LogSink {
+    fn write(&self, line: &str) {
+        if let Some(log_file) = &self.log_file
+            && let Err(e) = log_file.lock().write_line(line)
+        {
+            warn!("Failed to write sing-box log file: {}", e);
+        }
+
+        let line = LogLine::parse(line);
+        if self.logout {
+            line.emit();
+        }
+        self.error_log.lock().push(&line);
+        self.recent_logs.lock().push(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_restart_after_crash() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let binary = dir.child("sing-box");
+        // Crashes on the first run only
+        let script = format!(
+            "#!/bin/sh\n[ -e {0} ] && exec sleep 30\ntouch {0}\nsleep 0.2\nexit 1\n",
+            dir.child("crashed").display()
+        );
+        std::fs::write(&binary, script).unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let manager = ProcessManager::new(
+            dir.child("config.json"),
+            ProcessOptions {
+                binary: Some(binary),
+                ..Default::default()
+            },
+        );
+        let mut events = manager.subscribe();
+        let _supervisor = manager.supervise();
+
+        manager.start().await.unwrap();
+
+        let mut next = async || {
+            timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(next().await, ProcessEvent::Starting);
+        assert!(matches!(next().await, ProcessEvent::Started { .. }));
+        assert!(matches!(
+            next().await,
+            ProcessEvent::Exited { status: Some(_) }
+        ));
+        assert_eq!(next().await, ProcessEvent::Restarting { attempt: 1 });
+        assert_eq!(next().await, ProcessEvent::Starting);
+        assert!(matches!(next().await, ProcessEvent::Started { .. }));
+
+        manager.stop().await.unwrap();
+        assert!(matches!(next().await, ProcessEvent::Exited { .. }));
+        assert_eq!(next().await, ProcessEvent::Stopped);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stdout_logs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let binary = dir.child("xray");
+        // Xray writes its log to stdout without a log file
+        let script =
+            "#!/bin/sh\necho '2024/12/05 12:00:00 [Warning] core: no route'\nexec sleep 30\n";
+        std::fs::write(&binary, script).unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let manager = ProcessManager::new(
+            dir.child("config.json"),
+            ProcessOptions {
+                binary: Some(binary),
+                ..Default::default()
+            },
+        );
+        manager.start().await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while manager.recent_logs(1).is_empty() {
+            assert!(Instant::now() < deadline, "stdout not read");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let line = manager.recent_logs(1).remove(0);
+        assert_eq!(line.level, Some(log::LogLevel::Warn));
+        assert_eq!(line.message, "no route");
+        assert_eq!(manager.take_error_logs().entries.len(), 1);
+
+        manager.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::core::CoreKind;
+
+/// Identifies the sing-box a pod instance started, so the next instance can
+/// find it after a pod restart. The start time guards against the pid having
+/// been reused by an unrelated process.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PidFile {
+    pub pid: u32,
+    /// Start time in clock ticks since boot, field 22 of `/proc/<pid>/stat`.
+    pub start_time: u64,
+    /// Config the process was started with.
+    pub config: PathBuf,
+}
+
+impl PidFile {
+    pub fn new(pid: u32, config: PathBuf) -> Self {
+        Self {
+            pid,
+            start_time: process_start_time(pid).unwrap_or_default(),
+            config,
+        }
+    }
+
+    /// Where the pid file of the core running `config` is kept.
+    pub fn path(config: &Path) -> PathBuf {
+        config.with_file_name("sing-box.pid")
+    }
+
+    /// Reads the pid file at `path`, ignoring one the pod didn't write itself
+    /// since whatever pid it names may get killed.
+    pub fn read(path: &Path) -> Option<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = fs::symlink_metadata(path).ok()?;
+            if metadata.uid() != nix::unistd::geteuid().as_raw() || metadata.mode() & 0o022 != 0 {
+                return None;
+            }
+        }
+
+        let content = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn remove(path: &Path) {
+        let _ = fs::remove_file(path);
+    }
+
+    /// Stats API address in the config the process was started with.
+    pub fn stats_listen(&self) -> Option<String> {
+        let config: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&self.config).ok()?).ok()?;
+        [CoreKind::SingBox, CoreKind::Xray]
+            .into_iter()
+            .find_map(|kind| {
+                let listen = config.pointer(kind.core().stats_listen_pointer())?;
+                listen.as_str().map(str::to_string)
+            })
+    }
+
+    /// Whether the recorded process is still the one that is running.
+    pub fn is_alive(&self) -> bool {
+        self.start_time != 0 && process_start_time(self.pid) == Some(self.start_time)
+    }
+}
+
+/// Reads the start time of `pid`, `None` if it isn't running or the platform
+/// has no procfs.
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+
+    // The command name in field 2 may contain spaces, fields after it don't
+    let fields = stat.rsplit_once(')')?.1;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pid_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.child("sing-box.pid");
+
+        let pid_file = PidFile::new(std::process::id(), dir.child("config.json"));
+        assert!(pid_file.is_alive());
+
+        pid_file.write(&path).unwrap();
+        assert_eq!(PidFile::read(&path), Some(pid_file.clone()));
+
+        let reused = PidFile {
+            start_time: pid_file.start_time + 1,
+            ..pid_file
+        };
+        assert!(!reused.is_alive());
+
+        PidFile::remove(&path);
+        assert_eq!(PidFile::read(&path), None);
+    }
+}
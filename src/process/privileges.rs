@@ -0,0 +1,210 @@
+use std::io;
+use std::path::Path;
+use tokio::process::Command;
+
+#[cfg(target_os = "linux")]
+const CAP_NET_BIND_SERVICE: u32 = 10;
+#[cfg(target_os = "linux")]
+const CAP_NET_ADMIN: u32 = 12;
+
+/// User and group sing-box runs as instead of inheriting the pod's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    /// Looks up `user` and `group` by name or numeric id. The group defaults
+    /// to the user's primary group.
+    #[cfg(unix)]
+    pub fn lookup(user: &str, group: Option<&str>) -> io::Result<Self> {
+        use nix::unistd::{Gid, Group, Uid, User};
+
+        let not_found = |what: &str, name: &str| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} {} not found", what, name),
+            )
+        };
+
+        let user = match user.parse::<u32>() {
+            Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+            Err(_) => User::from_name(user),
+        }?
+        .ok_or_else(|| not_found("user", user))?;
+
+        let gid = match group {
+            Some(group) => {
+                match group.parse::<u32>() {
+                    Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+                    Err(_) => Group::from_name(group),
+                }?
+                .ok_or_else(|| not_found("group", group))?
+                .gid
+            }
+            None => user.gid,
+        };
+
+        Ok(Self {
+            uid: user.uid.as_raw(),
+            gid: gid.as_raw(),
+        })
+    }
+
+    #[cfg(windows)]
+    pub fn lookup(_user: &str, _group: Option<&str>) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "running sing-box as another user is not supported on Windows",
+        ))
+    }
+
+    /// Gives `path` to this user, so sing-box can read what the pod writes.
+    #[cfg(unix)]
+    pub fn chown(&self, path: &Path) -> io::Result<()> {
+        std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid))
+    }
+
+    #[cfg(windows)]
+    pub fn chown(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Gives `path` to this user's group only, so sing-box can read what the
+    /// pod writes without being able to change it.
+    #[cfg(unix)]
+    pub fn chgrp(&self, path: &Path) -> io::Result<()> {
+        std::os::unix::fs::chown(path, None, Some(self.gid))
+    }
+
+    #[cfg(windows)]
+    pub fn chgrp(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes the child switch to this user before exec, keeping only
+    /// CAP_NET_BIND_SERVICE, and CAP_NET_ADMIN when `net_admin` is set, as
+    /// ambient capabilities that survive the exec.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, command: &mut Command, net_admin: bool) -> io::Result<()> {
+        use nix::libc;
+
+        if !nix::unistd::geteuid().is_root() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "running sing-box as another user requires the pod to run as root",
+            ));
+        }
+
+        #[repr(C)]
+        struct CapHeader {
+            version: u32,
+            pid: libc::c_int,
+        }
+
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        struct CapData {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+
+        const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+        let caps: &'static [u32] = if net_admin {
+            &[CAP_NET_BIND_SERVICE, CAP_NET_ADMIN]
+        } else {
+            &[CAP_NET_BIND_SERVICE]
+        };
+        let mask = caps.iter().fold(0, |mask, cap| mask | 1 << cap);
+        let Self { uid, gid } = *self;
+
+        fn check(result: libc::c_long) -> io::Result<()> {
+            match result {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+
+        // Only async-signal-safe calls between fork and exec, nothing allocates
+        let pre_exec = move || unsafe {
+            // Keep the permitted capabilities across setuid
+            check(libc::prctl(libc::PR_SET_KEEPCAPS, 1 as libc::c_ulong).into())?;
+            check(libc::setgroups(1, &gid).into())?;
+            check(libc::setgid(gid).into())?;
+            check(libc::setuid(uid).into())?;
+
+            let header = CapHeader {
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            let data = [
+                CapData {
+                    effective: mask,
+                    permitted: mask,
+                    inheritable: mask,
+                },
+                CapData::default(),
+            ];
+            check(libc::syscall(libc::SYS_capset, &header, data.as_ptr()))?;
+
+            for &cap in caps {
+                check(
+                    libc::prctl(
+                        libc::PR_CAP_AMBIENT,
+                        libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                        cap as libc::c_ulong,
+                        0 as libc::c_ulong,
+                        0 as libc::c_ulong,
+                    )
+                    .into(),
+                )?;
+            }
+
+            Ok(())
+        };
+
+        unsafe { command.pre_exec(pre_exec) };
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _command: &mut Command, _net_admin: bool) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "running sing-box as another user is only supported on Linux",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_apply_drops_to_user() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let credentials = Credentials::lookup("65534", Some("65534")).unwrap();
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("id -u; id -g; grep CapAmb /proc/self/status");
+        credentials.apply(&mut command, false).unwrap();
+
+        let output = command.output().await.unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines[0], "65534");
+        assert_eq!(lines[1], "65534");
+        // CAP_NET_BIND_SERVICE is bit 10
+        assert!(lines[2].ends_with("0000000000000400"), "{}", lines[2]);
+    }
+}
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::config::diff::{ChangeKind, ConfigDiff};
+
+/// Why sing-box has to pick up a fetched config update.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reload {
+    Structural,
+    /// Users lost access, connections they hold must not survive.
+    Revocation,
+    /// Only users were added, existing connections may keep the old config.
+    UserAdditions,
+}
+
+/// Decides when a fetched config update has to reach sing-box.
+///
+/// sing-box has no API to add or remove inbound users at runtime. An update
+/// is either a SIGHUP, which drops every connection, or with a drain period a
+/// blue/green restart, which keeps them on the old instance until it drains.
+/// Structural changes and user revocations are applied right away, user
+/// additions can be held back for `user_delay` so that a burst of sign-ups
+/// costs a single reload.
+pub struct ReloadScheduler {
+    user_delay: Duration,
+    pending_users_since: Option<Instant>,
+}
+
+impl ReloadScheduler {
+    pub fn new(user_delay: Duration) -> Self {
+        Self {
+            user_delay,
+            pending_users_since: None,
+        }
+    }
+
+    /// Records the outcome of a fetch and returns why sing-box should be
+    /// reloaded now, if it should.
+    pub fn update(
+        &mut self,
+        change: Option<ChangeKind>,
+        diff: Option<&ConfigDiff>,
+    ) -> Option<Reload> {
+        match change {
+            Some(ChangeKind::Structural) => {
+                self.pending_users_since = None;
+                return Some(Reload::Structural);
+            }
+            Some(ChangeKind::UserOnly) => {
+                if diff.is_none_or(ConfigDiff::revokes_users) {
+                    self.pending_users_since = None;
+                    return Some(Reload::Revocation);
+                }
+
+                if self.pending_users_since.is_none() && !self.user_delay.is_zero() {
+                    info!(
+                        "Deferring user additions for up to {}s",
+                        self.user_delay.as_secs()
+                    );
+                }
+                self.pending_users_since.get_or_insert_with(Instant::now);
+            }
+            Some(ChangeKind::Cosmetic) | None => {}
+        }
+
+        match self.pending_users_since {
+            Some(since) if since.elapsed() >= self.user_delay => {
+                if !self.user_delay.is_zero() {
+                    info!("Applying deferred user additions");
+                }
+                self.pending_users_since = None;
+                Some(Reload::UserAdditions)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::diff::InboundUsersDiff;
+
+    fn users_diff(added: &[&str], removed: &[&str]) -> ConfigDiff {
+        ConfigDiff {
+            users: vec![InboundUsersDiff {
+                inbound: "ss-in".to_string(),
+                added: added.iter().map(|u| u.to_string()).collect(),
+                removed: removed.iter().map(|u| u.to_string()).collect(),
+                modified: Vec::new(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_user_additions_are_deferred() {
+        let mut scheduler = ReloadScheduler::new(Duration::from_secs(3600));
+
+        let added = users_diff(&["alice"], &[]);
+        assert_eq!(
+            scheduler.update(Some(ChangeKind::UserOnly), Some(&added)),
+            None
+        );
+        assert_eq!(scheduler.update(None, None), None);
+
+        let removed = users_diff(&[], &["alice"]);
+        assert_eq!(
+            scheduler.update(Some(ChangeKind::UserOnly), Some(&removed)),
+            Some(Reload::Revocation)
+        );
+
+        assert_eq!(
+            scheduler.update(Some(ChangeKind::UserOnly), Some(&added)),
+            None
+        );
+        assert_eq!(
+            scheduler.update(Some(ChangeKind::Structural), None),
+            Some(Reload::Structural)
+        );
+        assert_eq!(scheduler.update(None, None), None);
+    }
+
+    #[test]
+    fn test_deferred_additions_apply_after_delay() {
+        let mut scheduler = ReloadScheduler::new(Duration::ZERO);
+
+        let added = users_diff(&["alice"], &[]);
+        assert_eq!(
+            scheduler.update(Some(ChangeKind::UserOnly), Some(&added)),
+            Some(Reload::UserAdditions)
+        );
+        assert_eq!(scheduler.update(Some(ChangeKind::Cosmetic), None), None);
+    }
+}
@@ -0,0 +1,150 @@
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use tokio::process::Command;
+
+/// Build tag required for `experimental.v2ray_api`.
+pub const TAG_V2RAY_API: &str = "with_v2ray_api";
+
+/// First release accepting `reuse_addr` in inbound listen fields.
+pub const REUSE_ADDR_SINCE: (u32, u32, u32) = (1, 12, 0);
+
+/// Version and build information reported by `sing-box version`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SingBoxVersion {
+    pub version: String,
+    pub tags: Vec<String>,
+    pub environment: Option<String>,
+    pub revision: Option<String>,
+}
+
+impl SingBoxVersion {
+    pub async fn detect(binary: &Path) -> io::Result<Self> {
+        let output = Command::new(binary).arg("version").output().await?;
+
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "`{} version` exited with {}",
+                binary.display(),
+                output.status
+            )));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Self::parse(&stdout).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unrecognized sing-box version output: {:?}", stdout),
+            )
+        })
+    }
+
+    /// Parses output of the form:
+    ///
+    /// ```text
+    /// sing-box version 1.10.1
+    ///
+    /// Environment: go1.23.1 linux/amd64
+    /// Tags: with_gvisor,with_quic,with_utls
+    /// Revision: 3b0a9ed1a4ae04ae6d1a1f1d5bd2e0c7fd7d9b2c
+    /// CGO: disabled
+    /// ```
+    pub fn parse(output: &str) -> Option<Self> {
+        let mut lines = output.lines();
+
+        let version = lines
+            .next()?
+            .trim()
+            .strip_prefix("sing-box version ")?
+            .to_string();
+
+        let mut info = Self {
+            version,
+            ..Default::default()
+        };
+
+        for line in lines {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match key.trim() {
+                "Environment" => info.environment = Some(value.to_string()),
+                "Tags" => {
+                    info.tags = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|tag| !tag.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }
+                "Revision" => info.revision = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Some(info)
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// Major, minor and patch version, a pre-release counting as its release.
+    pub fn release(&self) -> Option<(u32, u32, u32)> {
+        let version = self.version.split(['-', '+']).next()?;
+        let mut parts = version.split('.').map(str::parse);
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => Some((major, minor, patch)),
+            _ => None,
+        }
+    }
+
+    /// Whether this is `release` or later, assumed for an unrecognized version.
+    pub fn at_least(&self, release: (u32, u32, u32)) -> bool {
+        self.release().is_none_or(|version| version >= release)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        let output = "sing-box version 1.10.1\n\n\
+                      Environment: go1.23.1 linux/amd64\n\
+                      Tags: with_gvisor,with_quic,with_v2ray_api\n\
+                      Revision: 3b0a9ed\n\
+                      CGO: disabled\n";
+
+        let version = SingBoxVersion::parse(output).unwrap();
+
+        assert_eq!(version.version, "1.10.1");
+        assert_eq!(version.environment.as_deref(), Some("go1.23.1 linux/amd64"));
+        assert_eq!(version.revision.as_deref(), Some("3b0a9ed"));
+        assert!(version.has_tag(TAG_V2RAY_API));
+        assert!(!version.has_tag("with_utls"));
+
+        let minimal = SingBoxVersion::parse("sing-box version 1.11.0\n").unwrap();
+        assert!(minimal.tags.is_empty());
+
+        assert!(SingBoxVersion::parse("Xray 1.8.24").is_none());
+    }
+
+    #[test]
+    fn test_version_order() {
+        let version = |version: &str| SingBoxVersion {
+            version: version.to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(version("1.12.0-beta.3").release(), Some((1, 12, 0)));
+        assert!(version("1.12.0-beta.3").at_least(REUSE_ADDR_SINCE));
+        assert!(version("1.13.1").at_least(REUSE_ADDR_SINCE));
+        assert!(!version("1.11.15").at_least(REUSE_ADDR_SINCE));
+        assert!(version("unknown").at_least(REUSE_ADDR_SINCE));
+    }
+}
@@ -0,0 +1,5 @@
+//! Helpers to test against the pod without a panel or sing-box, enabled by
+//! the `testing` feature. The fake sing-box is the `fake_singbox` example,
+//! built along with the integration tests.
+
+pub mod panel;
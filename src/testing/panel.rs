@@ -0,0 +1,204 @@
+use axum::Router;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use parking_lot::Mutex;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::api::server::ServerFetch;
+
+/// Token the mock panel accepts in `X-Proxy-Authorization`.
+pub const AUTH: &str = "test-token";
+
+const PATH: &str = "/api/provider/proxy";
+
+/// Failure the mock panel answers every request with until cleared.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Responds with this status and an empty body.
+    Status(StatusCode),
+    /// Waits this long before handling the request.
+    Delay(Duration),
+    /// Serves a config that isn't valid JSON.
+    BadJson,
+}
+
+#[derive(Default)]
+struct PanelState {
+    config: Mutex<Value>,
+    fault: Mutex<Option<Fault>>,
+    reports: Mutex<Vec<Value>>,
+    logs: Mutex<Vec<Value>>,
+    unauthorized: Mutex<usize>,
+}
+
+/// In-process panel serving a `ConfigResponse` at `url` and recording what
+/// the pod posts back, stopped when dropped.
+pub struct MockPanel {
+    pub url: String,
+    state: Arc<PanelState>,
+    server: JoinHandle<()>,
+}
+
+impl MockPanel {
+    pub async fn start(config: Value) -> Self {
+        let state = Arc::new(PanelState {
+            config: Mutex::new(config),
+            ..Default::default()
+        });
+
+        let app = Router::new()
+            .route(PATH, get(get_config).post(post_stats))
+            .route(&format!("{}/logs", PATH), post(post_logs))
+            .with_state(state.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}{}?id=test-node",
+            listener.local_addr().unwrap(),
+            PATH
+        );
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        Self { url, state, server }
+    }
+
+    /// A client for this panel using `AUTH`.
+    pub fn fetch(&self) -> ServerFetch {
+        ServerFetch::new(self.url.clone(), AUTH.to_string()).unwrap()
+    }
+
+    pub fn set_config(&self, config: Value) {
+        *self.state.config.lock() = config;
+    }
+
+    /// Makes every following request fail with `fault`, `None` to recover.
+    pub fn set_fault(&self, fault: Option<Fault>) {
+        *self.state.fault.lock() = fault;
+    }
+
+    /// Stats reports posted so far.
+    pub fn reports(&self) -> Vec<Value> {
+        self.state.reports.lock().clone()
+    }
+
+    /// Error log reports posted so far.
+    pub fn logs(&self) -> Vec<Value> {
+        self.state.logs.lock().clone()
+    }
+
+    /// Number of requests rejected for a missing or wrong token.
+    pub fn unauthorized(&self) -> usize {
+        *self.state.unauthorized.lock()
+    }
+}
+
+impl Drop for MockPanel {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// A `ConfigResponse` with one shadowsocks inbound listening on `port`.
+pub fn config_response(port: u16) -> Value {
+    json!({
+        "runtime": {
+            "log": { "level": "info" },
+            "dns": { "servers": [], "rules": [] },
+            "outbounds": [{ "type": "direct", "tag": "direct" }],
+            "route": { "rules": [] },
+            "inbounds": [{
+                "type": "shadowsocks",
+                "tag": "ss-in",
+                "listen": "127.0.0.1",
+                "listen_port": port,
+                "network": "tcp",
+                "method": "2022-blake3-aes-128-gcm",
+                "password": "server",
+                "users": [{ "name": "alice", "password": "a" }],
+            }],
+        },
+        "guardConfig": { "reportingCycle": 1 },
+    })
+}
+
+impl PanelState {
+    /// The response for an unauthorized or faulted request, after any delay.
+    async fn reject(&self, headers: &HeaderMap) -> Option<Response> {
+        if headers
+            .get("X-Proxy-Authorization")
+            .is_none_or(|auth| auth != AUTH)
+        {
+            *self.unauthorized.lock() += 1;
+            return Some(
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [("www-authenticate", "Token")],
+                    "invalid token",
+                )
+                    .into_response(),
+            );
+        }
+
+        let fault = self.fault.lock().clone();
+        match fault {
+            Some(Fault::Status(status)) => Some(status.into_response()),
+            Some(Fault::Delay(delay)) => {
+                tokio::time::sleep(delay).await;
+                None
+            }
+            Some(Fault::BadJson) | None => None,
+        }
+    }
+
+    /// Records a posted report, the pod sends them without a content type.
+    fn record(&self, reports: &Mutex<Vec<Value>>, body: &str) -> Response {
+        match serde_json::from_str(body) {
+            Ok(report) => {
+                reports.lock().push(report);
+                "ok".into_response()
+            }
+            Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        }
+    }
+}
+
+async fn get_config(State(state): State<Arc<PanelState>>, headers: HeaderMap) -> Response {
+    if let Some(response) = state.reject(&headers).await {
+        return response;
+    }
+
+    if matches!(*state.fault.lock(), Some(Fault::BadJson)) {
+        return "{\"runtime\":".into_response();
+    }
+    state.config.lock().to_string().into_response()
+}
+
+async fn post_stats(
+    State(state): State<Arc<PanelState>>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    match state.reject(&headers).await {
+        Some(response) => response,
+        None => state.record(&state.reports, &body),
+    }
+}
+
+async fn post_logs(
+    State(state): State<Arc<PanelState>>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    match state.reject(&headers).await {
+        Some(response) => response,
+        None => state.record(&state.logs, &body),
+    }
+}
@@ -0,0 +1,52 @@
+//! Shared by the integration tests, each includes it as `mod common`.
+
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+/// The fake sing-box example, which `cargo test --features testing` builds
+/// next to the test binaries in `target/<profile>/deps`.
+pub fn fake_singbox() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    let profile_dir = exe.parent().and_then(Path::parent).unwrap();
+    let binary = profile_dir
+        .join("examples")
+        .join(format!("fake_singbox{}", std::env::consts::EXE_SUFFIX));
+    assert!(
+        binary.is_file(),
+        "{} is missing, run the tests with `cargo test --features testing`",
+        binary.display()
+    );
+    binary
+}
+
+/// Writes a config with one shadowsocks inbound and the v2ray API.
+pub fn write_fake_config(path: &Path, port: u16, api_port: u16) {
+    let config = serde_json::json!({
+        "log": { "level": "info" },
+        "dns": { "servers": [], "rules": [] },
+        "outbounds": [{ "type": "direct", "tag": "direct" }],
+        "route": { "rules": [] },
+        "inbounds": [{
+            "type": "shadowsocks",
+            "tag": "ss-in",
+            "listen": "127.0.0.1",
+            "listen_port": port,
+            "network": "tcp",
+            "method": "2022-blake3-aes-128-gcm",
+            "password": "server",
+            "users": [{ "name": "alice", "password": "a" }],
+        }],
+        "experimental": {
+            "v2ray_api": {
+                "listen": format!("127.0.0.1:{}", api_port),
+                "stats": { "enabled": true, "inbounds": ["ss-in"], "outbounds": [], "users": ["alice"] },
+            },
+        },
+    });
+    std::fs::write(path, serde_json::to_vec(&config).unwrap()).unwrap();
+}
+
+pub fn free_port() -> u16 {
+    portpicker::pick_unused_port().unwrap()
+}
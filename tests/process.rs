@@ -0,0 +1,227 @@
+//! `ProcessManager` driving the fake sing-box.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::{fake_singbox, free_port, write_fake_config};
+use next_proxies_pod::ProcessError;
+use next_proxies_pod::api::v2ray_api::V2rayApi;
+use next_proxies_pod::core::CoreKind;
+use next_proxies_pod::process::event::ProcessEvent;
+use next_proxies_pod::process::{ProcessManager, ProcessOptions};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use std::os::unix::process::ExitStatusExt;
+use std::time::Duration;
+use temp_dir::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+#[tokio::test]
+async fn test_fake_singbox_lifecycle() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.child("config.json");
+    let (port, api_port) = (free_port(), free_port());
+    write_fake_config(&config, port, api_port);
+
+    let manager = ProcessManager::new(
+        config.clone(),
+        ProcessOptions {
+            binary: Some(fake_singbox()),
+            ..Default::default()
+        },
+    );
+    manager.start().await.unwrap();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut echo = [0; 4];
+    stream.read_exact(&mut echo).await.unwrap();
+    assert_eq!(&echo, b"ping");
+
+    let mut api = V2rayApi::new(
+        format!("http://127.0.0.1:{}", api_port),
+        CoreKind::SingBox.core().stats_package(),
+    )
+    .await
+    .unwrap();
+    let stats = serde_json::to_value(api.query_all_stats(true).await.unwrap()).unwrap();
+    assert_eq!(
+        stats["server"],
+        serde_json::json!([{ "id": "ss-in", "uplink": 4, "download": 4 }])
+    );
+    assert_eq!(stats["user"][0]["user"], "alice");
+
+    // A reload moves the inbound to the new port
+    let new_port = free_port();
+    write_fake_config(&config, new_port, api_port);
+    manager.reload().await.unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(("127.0.0.1", new_port)).await.is_err() {
+        assert!(Instant::now() < deadline, "inbound not moved by the reload");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let status = manager.stop().await.unwrap().unwrap();
+    assert!(status.success());
+}
+
+#[tokio::test]
+async fn test_blue_green_drain() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.child("config.json");
+    let port = free_port();
+    write_fake_config(&config, port, free_port());
+    let mut json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&config).unwrap()).unwrap();
+    json["inbounds"][0]["reuse_addr"] = true.into();
+    std::fs::write(&config, serde_json::to_vec(&json).unwrap()).unwrap();
+
+    let manager = ProcessManager::new(
+        config,
+        ProcessOptions {
+            binary: Some(fake_singbox()),
+            drain_period: Some(Duration::from_secs(1)),
+            ..Default::default()
+        },
+    );
+    manager.start().await.unwrap();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+
+    let wait_draining = async || {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while manager.draining_pid().is_none() {
+            assert!(Instant::now() < deadline, "no blue/green restart");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+
+    // Traffic on the draining process is queried before it is terminated
+    let restart = tokio::spawn({
+        let manager = manager.clone();
+        async move { manager.blue_green_restart().await }
+    });
+    wait_draining().await;
+    let mut echo = [0; 4];
+    stream.write_all(b"ping").await.unwrap();
+    stream.read_exact(&mut echo).await.unwrap();
+    // New connections only go to the new process
+    let mut streams = Vec::new();
+    for _ in 0..8 {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.read_exact(&mut echo).await.unwrap();
+        streams.push(stream);
+    }
+    restart.await.unwrap().unwrap();
+
+    let stats = serde_json::to_value(manager.take_unreported_stats().unwrap()).unwrap();
+    assert_eq!(stats["server"][0]["uplink"], 4);
+    assert!(manager.take_unreported_stats().is_none());
+    for stream in &mut streams {
+        stream.write_all(b"pong").await.unwrap();
+        stream.read_exact(&mut echo).await.unwrap();
+    }
+
+    // and terminated when the pod stops before the drain period is over
+    let restart = tokio::spawn({
+        let manager = manager.clone();
+        async move { manager.blue_green_restart().await }
+    });
+    wait_draining().await;
+    let old_pid = manager.draining_pid().unwrap();
+    manager.stop_draining().await;
+    assert!(kill(Pid::from_raw(old_pid as i32), None).is_err());
+    restart.await.unwrap().unwrap();
+
+    manager.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_blue_green_restarts_coalesce() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.child("config.json");
+    write_fake_config(&config, free_port(), free_port());
+    let mut json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&config).unwrap()).unwrap();
+    json["inbounds"][0]["reuse_addr"] = true.into();
+    std::fs::write(&config, serde_json::to_vec(&json).unwrap()).unwrap();
+
+    let manager = ProcessManager::new(
+        config,
+        ProcessOptions {
+            binary: Some(fake_singbox()),
+            drain_period: Some(Duration::from_millis(500)),
+            ..Default::default()
+        },
+    );
+    manager.start().await.unwrap();
+    let mut events = manager.subscribe();
+
+    // One restart runs, requests made meanwhile queue a single follow-up
+    for _ in 0..3 {
+        manager.spawn_blue_green_restart();
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while manager.draining_pid().is_none() {
+        assert!(Instant::now() < deadline, "no blue/green restart");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    for _ in 0..3 {
+        manager.spawn_blue_green_restart();
+    }
+
+    let mut started = 0;
+    while let Ok(Ok(event)) = tokio::time::timeout(Duration::from_secs(3), events.recv()).await {
+        if matches!(event, ProcessEvent::Started { .. }) {
+            started += 1;
+        }
+    }
+    assert_eq!(started, 2);
+
+    manager.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_stop_kills_hung_process() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.child("config.json");
+    write_fake_config(&config, free_port(), free_port());
+
+    let manager = ProcessManager::new(
+        config,
+        ProcessOptions {
+            binary: Some(fake_singbox()),
+            env: vec![("FAKE_SINGBOX_HANG".to_string(), "stop".to_string())],
+            stop_timeout: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+    manager.start().await.unwrap();
+
+    let status = manager.stop().await.unwrap().unwrap();
+    assert_eq!(status.signal(), Some(9));
+}
+
+#[tokio::test]
+async fn test_start_times_out() {
+    let dir = TempDir::new().unwrap();
+    let config = dir.child("config.json");
+    write_fake_config(&config, free_port(), free_port());
+
+    let manager = ProcessManager::new(
+        config,
+        ProcessOptions {
+            binary: Some(fake_singbox()),
+            env: vec![("FAKE_SINGBOX_HANG".to_string(), "start".to_string())],
+            ready_timeout: Duration::from_millis(500),
+            ..Default::default()
+        },
+    );
+
+    let e = manager.start().await.unwrap_err();
+    assert!(matches!(e, ProcessError::NotReady { .. }), "{}", e);
+    assert!(!manager.is_running().await);
+}
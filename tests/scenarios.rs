@@ -0,0 +1,259 @@
+//! End-to-end scenarios: the whole pod against the mock panel and the fake
+//! sing-box.
+
+#![cfg(unix)]
+
+mod common;
+
+use clap::Parser;
+use common::{fake_singbox, free_port};
+use next_proxies_pod::pod::{Args, PodError, run};
+use next_proxies_pod::process::pid_file::PidFile;
+use next_proxies_pod::testing::panel::{AUTH, Fault, MockPanel, config_response};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Duration;
+use temp_dir::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep};
+
+/// A pod running in-process against a mock panel and the fake sing-box.
+struct Pod {
+    runtime_dir: PathBuf,
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<Result<(), PodError>>,
+}
+
+impl Pod {
+    fn start(panel: &MockPanel, dir: &TempDir, extra_args: &[&str]) -> Self {
+        let runtime_dir = dir.child("runtime");
+        let mut argv = vec![
+            "next-proxies-pod".to_string(),
+            format!("--url={}", panel.url),
+            format!("--auth={}", AUTH),
+            format!("--singbox-bin={}", fake_singbox().display()),
+            format!("--runtime-dir={}", runtime_dir.display()),
+            "--user-reload-delay=0".to_string(),
+            "--stop-timeout=2".to_string(),
+            "--ready-timeout=5".to_string(),
+        ];
+        argv.extend(extra_args.iter().map(|arg| arg.to_string()));
+        let args = Args::try_parse_from(argv).unwrap();
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let handle = tokio::spawn(run(args, async {
+            let _ = shutdown_rx.await;
+        }));
+
+        Self {
+            runtime_dir,
+            shutdown,
+            handle,
+        }
+    }
+
+    /// Pid of the running sing-box, as recorded in its pid file.
+    fn pid(&self) -> Option<u32> {
+        PidFile::read(&self.runtime_dir.join("sing-box.pid"))
+            .filter(PidFile::is_alive)
+            .map(|pid_file| pid_file.pid)
+    }
+
+    async fn shutdown(self) {
+        self.shutdown.send(()).unwrap();
+        self.handle.await.unwrap().unwrap();
+    }
+}
+
+async fn wait_for(what: &str, mut done: impl AsyncFnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(15);
+    while !done().await {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Whether the inbound on `port` echoes.
+async fn echoes(port: u16) -> bool {
+    let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)).await else {
+        return false;
+    };
+    let mut echo = [0; 4];
+    stream.write_all(b"ping").await.is_ok()
+        && stream.read_exact(&mut echo).await.is_ok()
+        && &echo == b"ping"
+}
+
+fn uplink(report: &Value) -> u64 {
+    report["server"][0]["uplink"].as_u64().unwrap_or_default()
+}
+
+#[tokio::test]
+async fn test_scenario_report_and_reload() {
+    let dir = TempDir::new().unwrap();
+    let port = free_port();
+    let panel = MockPanel::start(config_response(port)).await;
+    let pod = Pod::start(&panel, &dir, &[]);
+
+    wait_for("the first report", async || !panel.reports().is_empty()).await;
+    let report = &panel.reports()[0];
+    assert_eq!(report["server"][0]["id"], "ss-in");
+    assert_eq!(report["user"][0]["user"], "alice");
+    assert_eq!(report["coreVersion"]["version"], "1.12.0-fake");
+    let pid = pod.pid().unwrap();
+
+    // Traffic through the inbound is reported
+    assert!(echoes(port).await);
+    wait_for("the traffic to be reported", async || {
+        panel.reports().iter().any(|report| uplink(report) == 4)
+    })
+    .await;
+
+    // A new port is reported as a diff and reloaded without a restart
+    let new_port = free_port();
+    panel.set_config(config_response(new_port));
+    wait_for("the config diff to be reported", async || {
+        panel
+            .reports()
+            .iter()
+            .any(|report| report["configDiffs"].is_array())
+    })
+    .await;
+    wait_for("the reloaded inbound", async || echoes(new_port).await).await;
+    assert_eq!(pod.pid(), Some(pid));
+
+    pod.shutdown().await;
+    assert_eq!(PidFile::read(&dir.child("runtime/sing-box.pid")), None);
+    assert!(!echoes(new_port).await);
+}
+
+#[tokio::test]
+async fn test_scenario_crash_and_panel_outage() {
+    let dir = TempDir::new().unwrap();
+    let port = free_port();
+    let panel = MockPanel::start(config_response(port)).await;
+    let crash_marker = format!(
+        "--singbox-env=FAKE_SINGBOX_CRASH_MARKER={}",
+        dir.child("crashed").display()
+    );
+    let pod = Pod::start(
+        &panel,
+        &dir,
+        &[
+            "--singbox-env=FAKE_SINGBOX_CRASH_AFTER_MS=1500",
+            &crash_marker,
+        ],
+    );
+
+    // The first sing-box crashes and is restarted
+    wait_for("sing-box to start", async || pod.pid().is_some()).await;
+    let first_pid = pod.pid().unwrap();
+    wait_for("sing-box to be restarted", async || {
+        pod.pid().is_some_and(|pid| pid != first_pid)
+    })
+    .await;
+    let pid = pod.pid().unwrap();
+
+    // Stats come from the new process once the API reconnected
+    wait_for("the restarted inbound", async || echoes(port).await).await;
+    wait_for("the traffic to be reported", async || {
+        panel.reports().iter().any(|report| uplink(report) == 4)
+    })
+    .await;
+
+    // Traffic served while the panel is down
+    panel.set_fault(Some(Fault::Status(StatusCode::SERVICE_UNAVAILABLE)));
+    sleep(Duration::from_millis(200)).await;
+    let posted = panel.reports().len();
+    assert!(echoes(port).await);
+    sleep(Duration::from_millis(2500)).await;
+    assert_eq!(pod.pid(), Some(pid));
+
+    // is in the first report once it is back
+    panel.set_fault(None);
+    wait_for("reports after the outage", async || {
+        panel.reports().len() > posted
+    })
+    .await;
+    assert_eq!(uplink(&panel.reports()[posted]), 4);
+
+    pod.shutdown().await;
+    assert_eq!(PidFile::read(&dir.child("runtime/sing-box.pid")), None);
+}
+
+#[tokio::test]
+async fn test_scenario_panel_down_at_boot() {
+    let dir = TempDir::new().unwrap();
+    let port = free_port();
+    let panel = MockPanel::start(config_response(port)).await;
+    panel.set_fault(Some(Fault::Status(StatusCode::SERVICE_UNAVAILABLE)));
+    let pod = Pod::start(&panel, &dir, &[]);
+
+    // The node keeps trying to start while the panel is down
+    sleep(Duration::from_millis(1500)).await;
+    assert_eq!(pod.pid(), None);
+    assert!(!pod.handle.is_finished());
+
+    panel.set_fault(None);
+    wait_for("sing-box to start", async || pod.pid().is_some()).await;
+    wait_for("the first report", async || !panel.reports().is_empty()).await;
+
+    pod.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_scenario_token_rejected_at_boot() {
+    let dir = TempDir::new().unwrap();
+    let panel = MockPanel::start(config_response(free_port())).await;
+    panel.set_fault(Some(Fault::Status(StatusCode::UNAUTHORIZED)));
+    let pod = Pod::start(&panel, &dir, &[]);
+
+    // The node waits out the token backoff instead of ending the pod
+    sleep(Duration::from_millis(1500)).await;
+    assert_eq!(pod.pid(), None);
+    assert!(!pod.handle.is_finished());
+
+    pod.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_scenario_adopt() {
+    let dir = TempDir::new().unwrap();
+    let port = free_port();
+    let panel = MockPanel::start(config_response(port)).await;
+
+    let pod = Pod::start(&panel, &dir, &["--adopt"]);
+    wait_for("the first report", async || !panel.reports().is_empty()).await;
+    let pid = pod.pid().unwrap();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    // stderr goes to the log pipe rather than a pipe that dies with the pod
+    #[cfg(target_os = "linux")]
+    assert_eq!(
+        std::fs::read_link(format!("/proc/{}/fd/2", pid)).unwrap(),
+        dir.child("runtime/sing-box.log.pipe")
+    );
+    pod.shutdown().await;
+
+    // The next instance takes over sing-box, its stats API and connections
+    let posted = panel.reports().len();
+    let pod = Pod::start(&panel, &dir, &["--adopt"]);
+    wait_for("reports from the new instance", async || {
+        panel.reports().len() > posted + 1
+    })
+    .await;
+    assert_eq!(pod.pid(), Some(pid));
+
+    let mut echo = [0; 4];
+    stream.write_all(b"ping").await.unwrap();
+    stream.read_exact(&mut echo).await.unwrap();
+    assert_eq!(&echo, b"ping");
+
+    pod.shutdown().await;
+    kill(Pid::from_raw(pid as i32), Signal::SIGTERM).unwrap();
+}